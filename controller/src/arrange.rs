@@ -0,0 +1,364 @@
+//! Plans how to arrange the pieces physically for a given position, e.g. to set up the board for a
+//! new game. The pieces are compared by their type and colour, as letters like in a FEN: upper case
+//! for white and lower case for black. The difference between the board plus the trays and the
+//! target is turned into a sequence of [`Step`]s, each carrying one piece.
+//!
+//! Pieces that already stand on the right square stay where they are. A missing piece is taken from
+//! a square where it isn't needed, or from the tray if there is none. A square that is occupied by
+//! a wrong piece is cleared first, and pieces that aren't needed anymore are put into the tray.
+
+use std::collections::BTreeMap;
+
+use chers::{PieceType, Square};
+use notation::Board;
+
+use crate::graveyard::{self, Graveyard};
+use crate::planner::Point;
+
+/// Where a piece can stand.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Place {
+    /// The square with the given index, cf. [`Board`].
+    Square(usize),
+    /// A slot of the tray of the given colour, `true` for white.
+    Slot(bool, usize),
+    /// The place of the spare queen of the given colour with its coordinates, cf.
+    /// [`crate::config::Promotion`].
+    Reserve(bool, (i32, i32)),
+}
+
+/// Carrying a piece from one place to another.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Step {
+    pub piece: char,
+    pub from: Place,
+    pub to: Place,
+}
+
+impl Step {
+    /// Updates the board and the trays after the piece has been carried.
+    pub fn apply(&self, board: &mut Board, graveyard: &mut Graveyard) {
+        let white = self.piece.is_ascii_uppercase();
+        match self.from {
+            Place::Square(i) => board[i] = None,
+            Place::Slot(white, slot) => {
+                graveyard.remove(white, slot);
+            }
+            Place::Reserve(..) => {}
+        }
+        match self.to {
+            Place::Square(i) => board[i] = Some(self.piece),
+            Place::Slot(_, slot) => {
+                let piece = graveyard::parse_piece_type(&self.piece.to_string())
+                    .expect("only pieces are carried");
+                let added = graveyard.add(white, piece);
+                debug_assert_eq!(added, Some(slot));
+            }
+            Place::Reserve(..) => {}
+        }
+    }
+}
+
+/// An arrangement that is being carried out one step at a time.
+#[derive(Debug, Clone)]
+pub struct Arrangement {
+    /// The complete FEN of the position that is set up.
+    pub fen: String,
+    pub steps: Vec<Step>,
+    /// The number of steps that have been carried out.
+    pub done: usize,
+    /// The pieces on the board after the steps that have been carried out.
+    pub board: Board,
+    /// The pieces that stand in for promoted pieces once the position is set up.
+    pub substitutes: Vec<(Square, PieceType)>,
+    /// How many moves of [`crate::robochess::RoboChess::history`] are kept once the position is
+    /// set up.
+    pub history: usize,
+    /// Whether the spare queens are used once the position is set up.
+    pub reserve_white_used: bool,
+    pub reserve_black_used: bool,
+}
+
+/// Returns the letter of a piece, e.g. `N` for a white knight.
+pub fn piece_letter(white: bool, piece: PieceType) -> char {
+    let c = piece.to_string().chars().next().unwrap_or('?');
+    if white {
+        c.to_ascii_uppercase()
+    } else {
+        c.to_ascii_lowercase()
+    }
+}
+
+/// Returns the colour and the name of a piece, e.g. `white knight`.
+pub fn piece_name(c: char) -> String {
+    let color = if c.is_ascii_uppercase() {
+        "white"
+    } else {
+        "black"
+    };
+    let name = match c.to_ascii_lowercase() {
+        'p' => "pawn",
+        'n' => "knight",
+        'b' => "bishop",
+        'r' => "rook",
+        'q' => "queen",
+        'k' => "king",
+        _ => "piece",
+    };
+    format!("{} {}", color, name)
+}
+
+/// Returns the centre of the square with the given index.
+pub fn center(square: usize) -> Point {
+    ((square % 8) as f32 + 0.5, (square / 8) as f32 + 0.5)
+}
+
+fn distance(a: usize, b: usize) -> f32 {
+    let (a, b) = (center(a), center(b));
+    (a.0 - b.0).abs() + (a.1 - b.1).abs()
+}
+
+/// The state of the board and the trays while the steps are planned.
+struct Planner {
+    board: Board,
+    graveyard: Graveyard,
+    target: Board,
+    steps: Vec<Step>,
+}
+
+impl Planner {
+    /// Returns whether the piece on the square has to move away.
+    fn misplaced(&self, square: usize) -> bool {
+        self.board[square].is_some() && self.board[square] != self.target[square]
+    }
+
+    /// Returns the squares that still have to get their piece.
+    fn missing(&self) -> Vec<usize> {
+        (0..64)
+            .filter(|&i| self.target[i].is_some() && self.board[i] != self.target[i])
+            .collect()
+    }
+
+    /// Returns whether the piece on the square is needed on another square.
+    fn needed(&self, square: usize) -> bool {
+        let piece = self.board[square];
+        self.missing().iter().any(|&i| self.target[i] == piece)
+    }
+
+    fn carry(&mut self, piece: char, from: Place, to: Place) {
+        let step = Step { piece, from, to };
+        step.apply(&mut self.board, &mut self.graveyard);
+        self.steps.push(step);
+    }
+
+    /// Puts the piece on the square into the first free slot of its tray.
+    fn put_into_tray(&mut self, square: usize) -> Result<(), String> {
+        let piece = self.board[square].expect("only pieces are put into the tray");
+        let white = piece.is_ascii_uppercase();
+        let slot = self.graveyard.first_free(white).ok_or_else(|| {
+            let color = if white { "white" } else { "black" };
+            format!("the {} tray is full", color)
+        })?;
+        self.carry(piece, Place::Square(square), Place::Slot(white, slot));
+        Ok(())
+    }
+
+    /// Brings the right piece to an empty square, preferably from the nearest square where it
+    /// isn't needed. Returns false if there is no such piece.
+    fn fill(&mut self, square: usize) -> bool {
+        let piece = match self.target[square] {
+            Some(piece) => piece,
+            None => return false,
+        };
+        let from = (0..64)
+            .filter(|&i| self.board[i] == Some(piece) && self.misplaced(i))
+            .min_by(|&a, &b| {
+                distance(a, square)
+                    .partial_cmp(&distance(b, square))
+                    .unwrap()
+            });
+        if let Some(from) = from {
+            self.carry(piece, Place::Square(from), Place::Square(square));
+            return true;
+        }
+        let white = piece.is_ascii_uppercase();
+        let slot = graveyard::parse_piece_type(&piece.to_string())
+            .and_then(|piece_type| self.graveyard.find(white, piece_type));
+        match slot {
+            Some(slot) => {
+                self.carry(piece, Place::Slot(white, slot), Place::Square(square));
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Plans the steps that turn the pieces on `board` and in the `graveyard` into `target`. Fails
+/// with an explanation if pieces are missing or a tray is too small.
+pub fn plan(board: &Board, graveyard: &Graveyard, target: &Board) -> Result<Vec<Step>, String> {
+    // check that there are enough pieces before planning anything
+    let mut balance: BTreeMap<char, i32> = BTreeMap::new();
+    for i in 0..64 {
+        if let Some(piece) = board[i] {
+            *balance.entry(piece).or_default() += 1;
+        }
+        if let Some(piece) = target[i] {
+            *balance.entry(piece).or_default() -= 1;
+        }
+    }
+    for &white in &[true, false] {
+        for piece in graveyard.slots(white).iter().flatten() {
+            *balance.entry(piece_letter(white, *piece)).or_default() += 1;
+        }
+    }
+    let missing: Vec<String> = balance
+        .iter()
+        .filter(|&(_, &n)| n < 0)
+        .map(|(&piece, &n)| match -n {
+            1 => format!("a {}", piece_name(piece)),
+            n => format!("{} {}s", n, piece_name(piece)),
+        })
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!(
+            "there are not enough pieces, {} missing",
+            missing.join(", ")
+        ));
+    }
+
+    let mut planner = Planner {
+        board: *board,
+        graveyard: graveyard.clone(),
+        target: *target,
+        steps: Vec::new(),
+    };
+    loop {
+        let missing = planner.missing();
+        if missing.is_empty() {
+            break;
+        }
+        // bring pieces to the empty squares first, that doesn't require any detours
+        let filled = missing
+            .iter()
+            .any(|&i| planner.board[i].is_none() && planner.fill(i));
+        if filled {
+            continue;
+        }
+        // every square that is missing its piece is occupied by a wrong one: clear one of them,
+        // preferably one whose piece isn't needed anywhere else
+        let blocked = missing
+            .iter()
+            .copied()
+            .filter(|&i| planner.board[i].is_some())
+            .min_by_key(|&i| planner.needed(i))
+            .expect("enough pieces are available");
+        planner.put_into_tray(blocked)?;
+    }
+    // put everything that isn't needed anymore into the tray
+    for i in 0..64 {
+        if planner.misplaced(i) {
+            planner.put_into_tray(i)?;
+        }
+    }
+    Ok(planner.steps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use notation::parse_board;
+
+    const START: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+    /// Carries out the steps on a copy of the board and the graveyard.
+    fn apply(board: &Board, graveyard: &Graveyard, steps: &[Step]) -> (Board, Graveyard) {
+        let (mut board, mut graveyard) = (*board, graveyard.clone());
+        for step in steps {
+            match step.from {
+                Place::Square(i) => assert_eq!(board[i], Some(step.piece)),
+                Place::Slot(white, slot) => assert!(graveyard.slots(white)[slot].is_some()),
+                Place::Reserve(..) => {}
+            }
+            if let Place::Square(i) = step.to {
+                assert_eq!(board[i], None, "{:?} is occupied", step);
+            }
+            step.apply(&mut board, &mut graveyard);
+        }
+        (board, graveyard)
+    }
+
+    #[test]
+    fn nothing_to_do() {
+        let board = parse_board(START).unwrap();
+        assert_eq!(plan(&board, &Graveyard::new(), &board), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn reset_after_a_game() {
+        // 1. e4 d5 2. exd5 Qxd5 3. Nc3
+        let board =
+            parse_board("rnb1kbnr/ppp1pppp/8/3q4/8/2N5/PPPP1PPP/R1BQKBNR b KQkq - 1 3").unwrap();
+        let mut graveyard = Graveyard::new();
+        graveyard.add(true, PieceType::PAWN);
+        graveyard.add(false, PieceType::PAWN);
+        let target = parse_board(START).unwrap();
+
+        let steps = plan(&board, &graveyard, &target).unwrap();
+        // the knight and the queen move back, both pawns come from the trays
+        assert_eq!(steps.len(), 4);
+        let (board, graveyard) = apply(&board, &graveyard, &steps);
+        assert_eq!(board, target);
+        assert_eq!(graveyard, Graveyard::new());
+    }
+
+    #[test]
+    fn one_piece_moved() {
+        let board = parse_board(START).unwrap();
+        let target =
+            parse_board("rnbqkbnr/pppppppp/8/8/8/5N2/PPPPPPPP/RNBQKB1R b KQkq - 1 1").unwrap();
+        assert_eq!(
+            plan(&board, &Graveyard::new(), &target),
+            Ok(vec![Step {
+                piece: 'N',
+                from: Place::Square(6),
+                to: Place::Square(21),
+            }])
+        );
+    }
+
+    #[test]
+    fn swapped_pieces() {
+        let board = parse_board("4k3/8/8/8/8/8/8/RN2K3 w - - 0 1").unwrap();
+        let target = parse_board("4k3/8/8/8/8/8/8/NR2K3 w - - 0 1").unwrap();
+        let steps = plan(&board, &Graveyard::new(), &target).unwrap();
+        // one of them has to make way through the tray
+        assert_eq!(steps.len(), 3);
+        let (board, graveyard) = apply(&board, &Graveyard::new(), &steps);
+        assert_eq!(board, target);
+        assert_eq!(graveyard, Graveyard::new());
+    }
+
+    #[test]
+    fn surplus_pieces_go_to_the_tray() {
+        let board = parse_board("4k3/8/8/8/8/8/8/Q3K1Q1 w - - 0 1").unwrap();
+        let target = parse_board("4k3/8/8/8/8/8/8/4K3 w - - 0 1").unwrap();
+        let steps = plan(&board, &Graveyard::new(), &target).unwrap();
+        let (board, graveyard) = apply(&board, &Graveyard::new(), &steps);
+        assert_eq!(board, target);
+        assert_eq!(graveyard.find(true, PieceType::QUEEN), Some(0));
+        assert_eq!(graveyard.find_any(true), Some(0));
+        assert_eq!(graveyard.slots(true)[1], Some(PieceType::QUEEN));
+    }
+
+    #[test]
+    fn missing_pieces() {
+        let board = parse_board("4k3/8/8/8/8/8/8/4K3 w - - 0 1").unwrap();
+        let target = parse_board("3qk3/pp6/8/8/8/8/8/4K3 w - - 0 1").unwrap();
+        assert_eq!(
+            plan(&board, &Graveyard::new(), &target),
+            Err("there are not enough pieces, 2 black pawns, a black queen missing".to_string())
+        );
+    }
+}
@@ -0,0 +1,194 @@
+//! Calibration of the board geometry. The operator moves the magnet over the centres of a1, h1, a8
+//! and h8 and confirms each position. From these measurements an affine transform from points on
+//! the board (cf. [`crate::planner`]) to coordinates is fitted, which also covers boards that are
+//! mounted slightly rotated or skewed.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use chers::Square;
+use serde::{Deserialize, Serialize};
+
+use crate::persist;
+use crate::planner::Point;
+
+/// The squares that are measured, in this order.
+const SQUARES: [Square; 4] = [Square::A1, Square::H1, Square::A8, Square::H8];
+
+/// Maps `(file, rank)` measured in squares to `(x, y)` measured in steps:
+/// `x = xx * file + xy * rank + x0` and `y = yx * file + yy * rank + y0`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Transform {
+    pub xx: f32,
+    pub xy: f32,
+    pub x0: f32,
+    pub yx: f32,
+    pub yy: f32,
+    pub y0: f32,
+}
+
+#[derive(Debug)]
+pub enum CalibrationError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    /// The measured positions don't span the board, e.g. because the same position was confirmed
+    /// for two squares.
+    Degenerate,
+}
+
+impl fmt::Display for CalibrationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CalibrationError::Io(e) => write!(f, "{}", e),
+            CalibrationError::Parse(e) => write!(f, "{}", e),
+            CalibrationError::Degenerate => write!(f, "the measured positions are degenerate"),
+        }
+    }
+}
+
+/// Solves the 3x3 system `m * v = b` with Cramer's rule.
+fn solve(m: [[f32; 3]; 3], b: [f32; 3]) -> Option<[f32; 3]> {
+    let det = |m: [[f32; 3]; 3]| {
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    };
+    let d = det(m);
+    if d.abs() < 1e-6 {
+        return None;
+    }
+    let mut res = [0.0; 3];
+    for (i, v) in res.iter_mut().enumerate() {
+        let mut mi = m;
+        for (row, &value) in mi.iter_mut().zip(b.iter()) {
+            row[i] = value;
+        }
+        *v = det(mi) / d;
+    }
+    Some(res)
+}
+
+impl Transform {
+    /// Applies the transform and rounds to whole steps.
+    pub fn apply(&self, (file, rank): Point) -> (i32, i32) {
+        let x = self.xx * file + self.xy * rank + self.x0;
+        let y = self.yx * file + self.yy * rank + self.y0;
+        (x.round() as i32, y.round() as i32)
+    }
+
+    /// Fits the transform to the measured points with least squares.
+    pub fn fit(measurements: &[(Point, (i32, i32))]) -> Result<Self, CalibrationError> {
+        // normal equations for the parameters (a, b, c) of `a * file + b * rank + c`
+        let mut m = [[0.0; 3]; 3];
+        let mut bx = [0.0; 3];
+        let mut by = [0.0; 3];
+        for &((file, rank), (x, y)) in measurements {
+            let row = [file, rank, 1.0];
+            for i in 0..3 {
+                for j in 0..3 {
+                    m[i][j] += row[i] * row[j];
+                }
+                bx[i] += row[i] * x as f32;
+                by[i] += row[i] * y as f32;
+            }
+        }
+        let x = solve(m, bx).ok_or(CalibrationError::Degenerate)?;
+        let y = solve(m, by).ok_or(CalibrationError::Degenerate)?;
+        let res = Self {
+            xx: x[0],
+            xy: x[1],
+            x0: x[2],
+            yx: y[0],
+            yy: y[1],
+            y0: y[2],
+        };
+        // a square has to cover at least one step in both directions
+        if (res.xx * res.yy - res.xy * res.yx).abs() < 1.0 {
+            return Err(CalibrationError::Degenerate);
+        }
+        Ok(res)
+    }
+
+    /// Loads a transform saved with [`Transform::save`]. Returns `None` if the file doesn't exist.
+    pub fn load(path: &Path) -> Result<Option<Self>, CalibrationError> {
+        match fs::read_to_string(path) {
+            Ok(content) => toml::from_str(&content)
+                .map(Some)
+                .map_err(CalibrationError::Parse),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(CalibrationError::Io(e)),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), CalibrationError> {
+        let content = toml::to_string(self).expect("a transform can always be serialized");
+        persist::write_atomically(path, &content).map_err(CalibrationError::Io)
+    }
+}
+
+/// The state of a running calibration.
+#[derive(Debug, Default)]
+pub struct Calibration {
+    measurements: Vec<(Point, (i32, i32))>,
+}
+
+impl Calibration {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The square the magnet has to be moved over next or `None` if all squares are measured.
+    pub fn next_square(&self) -> Option<Square> {
+        SQUARES.get(self.measurements.len()).copied()
+    }
+
+    /// Records `xy` as the position of the centre of [`Calibration::next_square`].
+    pub fn confirm(&mut self, center: Point, xy: (i32, i32)) {
+        self.measurements.push((center, xy));
+    }
+
+    /// Fits the transform to all confirmed positions.
+    pub fn finish(&self) -> Result<Transform, CalibrationError> {
+        Transform::fit(&self.measurements)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fit_rotated_board() {
+        // rotated by a bit less than one degree and shifted
+        let expected = Transform {
+            xx: -264.0,
+            xy: 4.0,
+            x0: 2200.0,
+            yx: -4.0,
+            yy: 264.0,
+            y0: 20.0,
+        };
+        let measurements: Vec<_> = [(0.5, 0.5), (7.5, 0.5), (0.5, 7.5), (7.5, 7.5)]
+            .iter()
+            .map(|&p| (p, expected.apply(p)))
+            .collect();
+        let fitted = Transform::fit(&measurements).unwrap();
+        for &(p, xy) in &measurements {
+            assert_eq!(fitted.apply(p), xy);
+        }
+        assert!((fitted.xy - expected.xy).abs() < 0.01);
+        assert!((fitted.yx - expected.yx).abs() < 0.01);
+    }
+
+    #[test]
+    fn same_position_twice() {
+        let measurements: Vec<_> = [(0.5, 0.5), (7.5, 0.5), (0.5, 7.5), (7.5, 7.5)]
+            .iter()
+            .map(|&p| (p, (1000, 1000)))
+            .collect();
+        assert!(Transform::fit(&measurements).is_err());
+    }
+}
@@ -0,0 +1,492 @@
+//! The configuration of the robot, which is loaded from a toml file at startup. Every value has a
+//! default, so the file only has to contain the values that differ from the defaults. See
+//! `robochess.toml` for an example with all values.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chers::{Color, Square};
+use serde::{Deserialize, Serialize};
+
+use crate::calibration::Transform;
+use crate::graveyard::{slot_center, SLOTS};
+use crate::planner::Point;
+use crate::robochess::square_center;
+use crate::stepper::Profile;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub pins: Pins,
+    pub geometry: Geometry,
+    pub timing: Timing,
+    pub engine: Engine,
+    pub promotion: Promotion,
+    pub replay: Replay,
+    pub server: Server,
+}
+
+/// The gpio pins (bcm numbering) the hardware is connected to.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Pins {
+    pub m1_step: u8,
+    pub m1_dir: u8,
+    pub m2_step: u8,
+    pub m2_dir: u8,
+    /// The end stop at the bottom.
+    pub b1: u8,
+    /// The end stop on the right.
+    pub b2: u8,
+    pub servo: u8,
+}
+
+/// Positions on the board measured in steps from the home position.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Geometry {
+    /// Distance between the home position and the outer edge of the h file.
+    pub x_offset: i32,
+    /// Distance between the home position and the outer edge of the first rank.
+    pub y_offset: i32,
+    /// Added to the y coordinate when putting down a piece, because the magnet drags the piece a
+    /// bit behind.
+    pub y_placement_offset: i32,
+    pub square_size: i32,
+    pub min_x: i32,
+    pub max_x: i32,
+    pub min_y: i32,
+    pub max_y: i32,
+    /// Where the result of the board calibration is stored.
+    pub calibration_file: PathBuf,
+    /// The result of the board calibration, which replaces the offsets and the square size for
+    /// positions on the board and in the trays next to it. It is loaded from `calibration_file`.
+    #[serde(skip)]
+    pub calibration: Option<Transform>,
+    /// Where the occupied slots of the trays are stored, cf. [`crate::graveyard`].
+    pub graveyard_file: PathBuf,
+    /// Where the game is saved after every move, so that it can be resumed after a restart, cf.
+    /// [`crate::journal`].
+    pub journal_file: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timing {
+    /// Used for carrying pieces.
+    pub slow: Profile,
+    /// Used for moving without a piece.
+    pub fast: Profile,
+    /// Seconds between two steps during the init sequence.
+    pub homing_delay: f32,
+    /// Seconds the carriage may take to reach an endstop during the init sequence.
+    pub homing_timeout: f32,
+    /// Seconds to wait for the servo and the pieces to settle.
+    pub settle_time: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Side {
+    White,
+    Black,
+}
+
+impl Side {
+    pub fn color(self) -> Color {
+        match self {
+            Side::White => Color::WHITE,
+            Side::Black => !Color::WHITE,
+        }
+    }
+}
+
+/// The defaults for the engine, which can be changed for each game with
+/// [`crate::protocol::Command::Engine`].
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Engine {
+    /// The side the engine plays or `None` if two humans play against each other.
+    pub side: Option<Side>,
+    /// The maximal search depth in plies.
+    pub depth: u32,
+    /// If set, the search is deepened one ply at a time and no further ply is started once this
+    /// many seconds have passed.
+    pub time: Option<f32>,
+}
+
+/// What happens if a pawn is promoted, but there is no piece of the promoted type in the tray.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PromotionPolicy {
+    /// The game is paused until a human has put the piece on the board and confirmed it with
+    /// [`crate::protocol::Command::Placed`].
+    Ask,
+    /// Another piece of the tray, preferably a rook, is put on the board in its place, e.g. to be
+    /// turned upside down. If the tray is empty the game is paused as with `Ask`.
+    Substitute,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Promotion {
+    pub policy: PromotionPolicy,
+    /// Where a spare white queen stands next to the board, measured in steps. It is used if there
+    /// is no white queen in the tray.
+    pub reserve_white: Option<(i32, i32)>,
+    /// Where a spare black queen stands next to the board.
+    pub reserve_black: Option<(i32, i32)>,
+}
+
+/// How the games of a PGN are replayed, cf. [`crate::protocol::Command::Import`].
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Replay {
+    /// Seconds between two moves.
+    pub pause: f32,
+    /// Whether the game is set up and replayed again after the last move, e.g. to test the
+    /// mechanics for a long time.
+    pub repeat: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Server {
+    pub address: String,
+    pub port: u16,
+    /// Where the games are saved as PGN files, cf. [`crate::pgn`].
+    pub games_directory: PathBuf,
+}
+
+impl Default for Pins {
+    fn default() -> Self {
+        Self {
+            m1_step: 27,
+            m1_dir: 17,
+            m2_step: 6,
+            m2_dir: 26,
+            b1: 16,
+            b2: 5,
+            servo: 13,
+        }
+    }
+}
+
+impl Default for Geometry {
+    fn default() -> Self {
+        Self {
+            x_offset: 100,
+            y_offset: 0,
+            y_placement_offset: 50,
+            square_size: 264,
+            min_x: 0,
+            max_x: 2400,
+            min_y: 0,
+            max_y: 2200,
+            calibration_file: PathBuf::from("calibration.toml"),
+            calibration: None,
+            graveyard_file: PathBuf::from("graveyard.toml"),
+            journal_file: PathBuf::from("journal.toml"),
+        }
+    }
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Self {
+            slow: Profile {
+                max_speed: 150.0,
+                acceleration: 600.0,
+                jerk: 6000.0,
+            },
+            fast: Profile {
+                max_speed: 400.0,
+                acceleration: 1500.0,
+                jerk: 15000.0,
+            },
+            homing_delay: 0.008,
+            homing_timeout: 30.0,
+            settle_time: 0.5,
+        }
+    }
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self {
+            side: Some(Side::Black),
+            depth: 3,
+            time: None,
+        }
+    }
+}
+
+impl Default for Promotion {
+    fn default() -> Self {
+        Self {
+            policy: PromotionPolicy::Ask,
+            reserve_white: None,
+            reserve_black: None,
+        }
+    }
+}
+
+impl Default for Replay {
+    fn default() -> Self {
+        Self {
+            pause: 3.0,
+            repeat: false,
+        }
+    }
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self {
+            address: "0.0.0.0".to_string(),
+            port: 8080,
+            games_directory: PathBuf::from("games"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "could not read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "could not parse {}: {}", path.display(), e),
+            ConfigError::Invalid(msg) => write!(f, "invalid configuration: {}", msg),
+        }
+    }
+}
+
+fn invalid<T>(msg: String) -> Result<T, ConfigError> {
+    Err(ConfigError::Invalid(msg))
+}
+
+impl Config {
+    /// Loads and validates the configuration from the given file.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let content =
+            fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+        let config: Config =
+            toml::from_str(&content).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks that the values are consistent, so that the robot never moves out of its bounds.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let p = &self.pins;
+        let mut used = HashMap::new();
+        for &(name, pin) in &[
+            ("m1_step", p.m1_step),
+            ("m1_dir", p.m1_dir),
+            ("m2_step", p.m2_step),
+            ("m2_dir", p.m2_dir),
+            ("b1", p.b1),
+            ("b2", p.b2),
+            ("servo", p.servo),
+        ] {
+            if pin > 27 {
+                return invalid(format!("pins.{} = {} is not a gpio pin", name, pin));
+            }
+            if let Some(other) = used.insert(pin, name) {
+                return invalid(format!(
+                    "pins.{} and pins.{} both use pin {}",
+                    other, name, pin
+                ));
+            }
+        }
+
+        let g = &self.geometry;
+        if g.square_size <= 0 {
+            return invalid("geometry.square_size must be positive".to_string());
+        }
+        if let Some((x, y)) = g.unreachable_point() {
+            return invalid(format!(
+                "the board and the captured pieces need {} {}, but geometry.min_x, \
+                 geometry.max_x, geometry.min_y and geometry.max_y only allow x from {} to {} and \
+                 y from {} to {}",
+                x, y, g.min_x, g.max_x, g.min_y, g.max_y
+            ));
+        }
+
+        let t = &self.timing;
+        for &(name, profile) in &[("slow", &t.slow), ("fast", &t.fast)] {
+            if profile.max_speed <= 0.0 || profile.acceleration <= 0.0 || profile.jerk <= 0.0 {
+                return invalid(format!(
+                    "timing.{}.max_speed, acceleration and jerk must be positive",
+                    name
+                ));
+            }
+        }
+        if t.homing_delay <= 0.0 {
+            return invalid("timing.homing_delay must be positive".to_string());
+        }
+        if t.homing_timeout <= 0.0 {
+            return invalid("timing.homing_timeout must be positive".to_string());
+        }
+        if t.settle_time < 0.0 {
+            return invalid("timing.settle_time must not be negative".to_string());
+        }
+
+        self.engine.validate().map_err(ConfigError::Invalid)?;
+
+        for &(name, reserve) in &[
+            ("reserve_white", self.promotion.reserve_white),
+            ("reserve_black", self.promotion.reserve_black),
+        ] {
+            if let Some((x, y)) = reserve {
+                if !(g.min_x..=g.max_x).contains(&x) || !(g.min_y..=g.max_y).contains(&y) {
+                    return invalid(format!(
+                        "promotion.{} = [{}, {}] is out of bounds",
+                        name, x, y
+                    ));
+                }
+            }
+        }
+
+        if self.replay.pause < 0.0 {
+            return invalid("replay.pause must not be negative".to_string());
+        }
+
+        if self.server.port == 0 {
+            return invalid("server.port must not be 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Geometry {
+    /// Returns a point the gantry can't reach, if there is one: a corner of the board, along whose
+    /// edges the king is carried when castling, or a slot of the trays.
+    pub fn unreachable_point(&self) -> Option<(i32, i32)> {
+        let corners = [(0.0, 0.0), (8.0, 0.0), (0.0, 8.0), (8.0, 8.0)];
+        let slots =
+            (0..SLOTS).flat_map(|slot| vec![slot_center(true, slot), slot_center(false, slot)]);
+        corners
+            .iter()
+            .copied()
+            .chain(slots)
+            .map(|p| self.board_to_xy(p))
+            .find(|&(x, y)| {
+                !(self.min_x..=self.max_x).contains(&x) || !(self.min_y..=self.max_y).contains(&y)
+            })
+    }
+
+    pub fn get_square_coordinates(&self, sq: Square) -> (i32, i32) {
+        self.board_to_xy(square_center(sq))
+    }
+
+    /// Converts a point measured in squares (cf. [`crate::planner`]) to coordinates. If the board
+    /// has been calibrated the calibration is used, otherwise the board is assumed to be aligned
+    /// with the axes.
+    pub fn board_to_xy(&self, p: Point) -> (i32, i32) {
+        self.calibration
+            .unwrap_or(Transform {
+                xx: -self.square_size as f32,
+                xy: 0.0,
+                x0: (self.x_offset + 8 * self.square_size) as f32,
+                yx: 0.0,
+                yy: self.square_size as f32,
+                y0: self.y_offset as f32,
+            })
+            .apply(p)
+    }
+
+    /// Returns the coordinates of a slot of the tray of the given colour.
+    pub fn slot_coordinates(&self, white: bool, slot: usize) -> (i32, i32) {
+        self.board_to_xy(slot_center(white, slot))
+    }
+}
+
+impl Engine {
+    /// Returns the reason if the settings are invalid.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.depth == 0 {
+            return Err("engine.depth must be positive".to_string());
+        }
+        if matches!(self.time, Some(time) if time <= 0.0) {
+            return Err("engine.time must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_are_valid() {
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn example_file_is_valid() {
+        let config: Config = toml::from_str(include_str!("../robochess.toml")).unwrap();
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn partial_file() {
+        let config: Config = toml::from_str("[server]\nport = 1234\n").unwrap();
+        assert_eq!(config.server.port, 1234);
+        assert_eq!(config.server.address, "0.0.0.0");
+        assert_eq!(config.pins.servo, 13);
+    }
+
+    #[test]
+    fn duplicate_pins() {
+        let mut config = Config::default();
+        config.pins.b1 = config.pins.servo;
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid configuration: pins.b1 and pins.servo both use pin 13"
+        );
+    }
+
+    #[test]
+    fn engine_depth() {
+        let config: Config = toml::from_str("[engine]\ndepth = 0\n").unwrap();
+        assert_eq!(config.engine.side, Some(Side::Black));
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid configuration: engine.depth must be positive"
+        );
+    }
+
+    #[test]
+    fn tray_beyond_the_gantry() {
+        let config: Config = toml::from_str("[geometry]\ny_offset = 50\n").unwrap();
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid configuration: the board and the captured pieces need 2278 2228, but \
+             geometry.min_x, geometry.max_x, geometry.min_y and geometry.max_y only allow x from 0 \
+             to 2400 and y from 0 to 2200"
+        );
+    }
+
+    #[test]
+    fn promotion_reserve() {
+        let config: Config =
+            toml::from_str("[promotion]\npolicy = \"substitute\"\nreserve_white = [0, 5000]\n")
+                .unwrap();
+        assert_eq!(config.promotion.policy, PromotionPolicy::Substitute);
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid configuration: promotion.reserve_white = [0, 5000] is out of bounds"
+        );
+    }
+}
@@ -0,0 +1,50 @@
+//! The engine that plays against the human. The search runs on its own thread, so that the server
+//! keeps answering the clients in the meantime.
+
+use std::thread;
+use std::time::Instant;
+
+use chers::{BitMove, Position};
+
+use crate::config::Engine;
+
+/// Returns the move in coordinate notation, e.g. `e2e4` or `e7e8q`.
+pub fn coordinate_notation(m: BitMove) -> String {
+    if m.is_promotion() {
+        format!("{}{}{}", m.origin(), m.target(), m.promotion_piece())
+    } else {
+        format!("{}{}", m.origin(), m.target())
+    }
+}
+
+/// Searches the best move in the position and returns it in coordinate notation or `None` if there
+/// is no legal move.
+///
+/// `chers` can't interrupt a running search, so with a time budget the search is deepened one ply
+/// at a time and the budget only decides whether the next ply is started.
+pub fn search(fen: &str, settings: &Engine) -> Option<String> {
+    let mut position = Position::from_fen(fen).ok()?;
+    let m = match settings.time {
+        None => position.search(settings.depth),
+        Some(time) => {
+            let start = Instant::now();
+            let mut m = BitMove::NULL;
+            for depth in 1..=settings.depth {
+                m = position.search(depth);
+                if start.elapsed().as_secs_f32() >= time {
+                    break;
+                }
+            }
+            m
+        }
+    };
+    if m == BitMove::NULL {
+        return None;
+    }
+    Some(coordinate_notation(m))
+}
+
+/// Starts a search on a background thread and calls `done` with the result.
+pub fn start(fen: String, settings: Engine, done: impl FnOnce(Option<String>) + Send + 'static) {
+    thread::spawn(move || done(search(&fen, &settings)));
+}
@@ -0,0 +1,83 @@
+//! The errors of the controller. The gantry, [`crate::robochess::RoboChess`] and the server return
+//! them instead of panicking, so that a failed command is reported to the client that sent it and
+//! the server goes on serving the others.
+
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum RoboChessError {
+    /// The carriage was sent to coordinates outside of the bounds of the geometry.
+    OutOfBounds { x: i32, y: i32 },
+    /// The hardware doesn't behave as expected, e.g. an endstop that stays pressed.
+    Hardware(String),
+    /// The carriage didn't reach an endstop during the init sequence.
+    Homing(String),
+    /// The move is not legal in the current position.
+    IllegalMove(String),
+    /// The FEN can't be parsed.
+    InvalidFen(String),
+    /// The PGN can't be parsed or contains an illegal move.
+    InvalidPgn(String),
+    /// The pieces can't be arranged for a position, e.g. because there are not enough of them.
+    Arrangement(String),
+    /// The moves can't be taken back, e.g. because a captured piece is not in the tray.
+    Undo(String),
+    /// The board calibration failed, e.g. because the measured positions are degenerate.
+    Calibration(String),
+    /// The journal of the saved game is invalid, so the game can't be resumed.
+    Journal(String),
+    /// The request has to wait until something else is done, e.g. the pieces are being arranged,
+    /// another client is in control or the robot was interrupted in a move.
+    Busy(String),
+    /// A client sent a request that doesn't fit the protocol or the state of the game.
+    Protocol(String),
+    /// The websocket server can't be started.
+    Io(io::Error),
+}
+
+impl RoboChessError {
+    /// Returns the name of the variant in snake case, e.g. `busy`, so that clients can tell the
+    /// errors apart without parsing the message.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RoboChessError::OutOfBounds { .. } => "out_of_bounds",
+            RoboChessError::Hardware(_) => "hardware",
+            RoboChessError::Homing(_) => "homing",
+            RoboChessError::IllegalMove(_) => "illegal_move",
+            RoboChessError::InvalidFen(_) => "invalid_fen",
+            RoboChessError::InvalidPgn(_) => "invalid_pgn",
+            RoboChessError::Arrangement(_) => "arrangement",
+            RoboChessError::Undo(_) => "undo",
+            RoboChessError::Calibration(_) => "calibration",
+            RoboChessError::Journal(_) => "journal",
+            RoboChessError::Busy(_) => "busy",
+            RoboChessError::Protocol(_) => "protocol",
+            RoboChessError::Io(_) => "io",
+        }
+    }
+}
+
+impl fmt::Display for RoboChessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RoboChessError::OutOfBounds { x, y } => {
+                write!(f, "the coordinates {} {} are out of bounds", x, y)
+            }
+            RoboChessError::Hardware(e) => write!(f, "hardware fault: {}", e),
+            RoboChessError::Homing(e) => write!(f, "homing failed: {}", e),
+            RoboChessError::IllegalMove(m) => write!(f, "illegal move {}", m),
+            RoboChessError::InvalidFen(fen) => write!(f, "invalid fen {}", fen),
+            RoboChessError::InvalidPgn(e) => write!(f, "invalid pgn: {}", e),
+            RoboChessError::Arrangement(e) => write!(f, "can't arrange the pieces: {}", e),
+            RoboChessError::Calibration(e) => write!(f, "calibration failed: {}", e),
+            RoboChessError::Journal(e) => write!(f, "could not resume the saved game: {}", e),
+            RoboChessError::Undo(e) | RoboChessError::Busy(e) | RoboChessError::Protocol(e) => {
+                write!(f, "{}", e)
+            }
+            RoboChessError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for RoboChessError {}
@@ -0,0 +1,32 @@
+use crate::error::RoboChessError;
+
+/// The motion backend of the robot. [`crate::hbot::HBot`] drives the real hardware over the gpio
+/// pins and [`crate::simulator::SimulatedGantry`] only records the commands, so that
+/// [`crate::robochess::RoboChess`] can also run without a raspberry pi.
+///
+/// Coordinates outside of the bounds of the geometry are refused with
+/// [`RoboChessError::OutOfBounds`] without moving.
+pub trait Gantry {
+    /// Drives the carriage to its home position and resets the coordinates to `(0, 0)`. If it fails
+    /// the position is unknown and every move fails with [`RoboChessError::Homing`] until it
+    /// succeeds.
+    fn init_sequence(&mut self) -> Result<(), RoboChessError>;
+
+    /// Moves the carriage to the given coordinates at the speed used for carrying pieces.
+    fn move_to_xy_slow(&mut self, x: i32, y: i32) -> Result<(), RoboChessError>;
+
+    /// Moves the carriage to the given coordinates at the speed used for empty moves.
+    fn move_to_xy_fast(&mut self, x: i32, y: i32) -> Result<(), RoboChessError>;
+
+    /// Raises the magnet, which picks up the piece above it.
+    fn up(&mut self);
+
+    /// Lowers the magnet, which puts down the piece above it.
+    fn down(&mut self);
+
+    /// Returns the current coordinates of the carriage.
+    fn position(&self) -> (i32, i32);
+
+    /// Waits a short moment to let the piece and the mechanics settle.
+    fn wait(&self);
+}
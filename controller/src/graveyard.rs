@@ -0,0 +1,259 @@
+//! The trays next to the board where the captured pieces are put. The white pieces are lined up
+//! beside the h file starting at the first rank, the black pieces beside the a file starting at the
+//! eighth rank. Every tray has [`SLOTS`] numbered slots, each holding at most one piece.
+//!
+//! The occupied slots are saved after every change, so that the robot still knows where the
+//! captured pieces are after a restart. Like the journal, they are written with
+//! [`crate::persist::write_atomically`].
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use chers::PieceType;
+use serde::{Deserialize, Serialize};
+
+use crate::persist;
+use crate::planner::Point;
+
+/// The number of slots of each tray. They span the height of the board.
+pub const SLOTS: usize = 16;
+
+const PIECE_TYPES: [PieceType; 6] = [
+    PieceType::PAWN,
+    PieceType::KNIGHT,
+    PieceType::BISHOP,
+    PieceType::ROOK,
+    PieceType::QUEEN,
+    PieceType::KING,
+];
+
+/// Parses the letter of a piece type like `q`, regardless of its case.
+pub fn parse_piece_type(name: &str) -> Option<PieceType> {
+    PIECE_TYPES
+        .iter()
+        .find(|p| p.to_string().eq_ignore_ascii_case(name))
+        .copied()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Graveyard {
+    white: [Option<PieceType>; SLOTS],
+    black: [Option<PieceType>; SLOTS],
+}
+
+/// How a graveyard is saved: one letter per slot (`p`, `n`, `b`, `r`, `q` or `k`) and an empty
+/// string for a free slot.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Trays {
+    pub white: Vec<String>,
+    pub black: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum GraveyardError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for GraveyardError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GraveyardError::Io(e) => write!(f, "{}", e),
+            GraveyardError::Parse(e) => write!(f, "{}", e),
+            GraveyardError::Invalid(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl Default for Graveyard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Graveyard {
+    /// Creates a graveyard with empty trays.
+    pub fn new() -> Self {
+        Self {
+            white: [None; SLOTS],
+            black: [None; SLOTS],
+        }
+    }
+
+    /// Returns the slots of the tray of the given colour.
+    pub fn slots(&self, white: bool) -> &[Option<PieceType>; SLOTS] {
+        if white {
+            &self.white
+        } else {
+            &self.black
+        }
+    }
+
+    fn slots_mut(&mut self, white: bool) -> &mut [Option<PieceType>; SLOTS] {
+        if white {
+            &mut self.white
+        } else {
+            &mut self.black
+        }
+    }
+
+    /// Returns the first free slot or `None` if the tray is full.
+    pub fn first_free(&self, white: bool) -> Option<usize> {
+        self.slots(white).iter().position(|p| p.is_none())
+    }
+
+    /// Puts the piece into the first free slot and returns its number or `None` if the tray is
+    /// full.
+    pub fn add(&mut self, white: bool, piece: PieceType) -> Option<usize> {
+        let slot = self.first_free(white)?;
+        self.slots_mut(white)[slot] = Some(piece);
+        Some(slot)
+    }
+
+    /// Returns the first slot holding a piece of the given type.
+    pub fn find(&self, white: bool, piece: PieceType) -> Option<usize> {
+        self.slots(white).iter().position(|&p| p == Some(piece))
+    }
+
+    /// Returns the first occupied slot.
+    pub fn find_any(&self, white: bool) -> Option<usize> {
+        self.slots(white).iter().position(|p| p.is_some())
+    }
+
+    /// Takes the piece out of the slot, which can be reused afterwards.
+    pub fn remove(&mut self, white: bool, slot: usize) -> Option<PieceType> {
+        self.slots_mut(white)[slot].take()
+    }
+
+    /// Empties both trays.
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Loads a graveyard saved with [`Graveyard::save`]. Returns `None` if the file doesn't exist.
+    pub fn load(path: &Path) -> Result<Option<Self>, GraveyardError> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(GraveyardError::Io(e)),
+        };
+        let trays: Trays = toml::from_str(&content).map_err(GraveyardError::Parse)?;
+        Self::from_trays(&trays).map(Some)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), GraveyardError> {
+        let content =
+            toml::to_string(&self.to_trays()).expect("the trays can always be serialized");
+        persist::write_atomically(path, &content).map_err(GraveyardError::Io)
+    }
+
+    /// Parses the trays the way they are saved.
+    pub fn from_trays(trays: &Trays) -> Result<Self, GraveyardError> {
+        let mut res = Self::new();
+        for &(white, names) in &[(true, &trays.white), (false, &trays.black)] {
+            if names.len() > SLOTS {
+                return Err(GraveyardError::Invalid(format!(
+                    "a tray has only {} slots",
+                    SLOTS
+                )));
+            }
+            for (slot, name) in res.slots_mut(white).iter_mut().zip(names) {
+                if name.is_empty() {
+                    continue;
+                }
+                let piece = parse_piece_type(name)
+                    .ok_or_else(|| GraveyardError::Invalid(format!("unknown piece {}", name)))?;
+                *slot = Some(piece);
+            }
+        }
+        Ok(res)
+    }
+
+    /// Returns the trays the way they are saved.
+    pub fn to_trays(&self) -> Trays {
+        let names = |slots: &[Option<PieceType>; SLOTS]| {
+            slots
+                .iter()
+                .map(|p| p.map_or(String::new(), |p| p.to_string().to_ascii_lowercase()))
+                .collect()
+        };
+        Trays {
+            white: names(&self.white),
+            black: names(&self.black),
+        }
+    }
+}
+
+/// Returns the centre of a slot of the tray of the given colour, measured in squares (cf.
+/// [`crate::planner`]). The slots are half a square apart, a quarter of a square next to the board.
+pub fn slot_center(white: bool, slot: usize) -> Point {
+    let slot = slot as f32;
+    if white {
+        (8.25, slot / 2.0 + 0.25)
+    } else {
+        (-0.25, 8.25 - slot / 2.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Geometry;
+
+    #[test]
+    fn first_free_slot_is_reused() {
+        let mut graveyard = Graveyard::new();
+        assert_eq!(graveyard.add(false, PieceType::PAWN), Some(0));
+        assert_eq!(graveyard.add(false, PieceType::QUEEN), Some(1));
+        assert_eq!(graveyard.add(false, PieceType::ROOK), Some(2));
+        assert_eq!(graveyard.add(true, PieceType::KNIGHT), Some(0));
+
+        assert_eq!(graveyard.find(false, PieceType::QUEEN), Some(1));
+        assert_eq!(graveyard.remove(false, 1), Some(PieceType::QUEEN));
+        assert_eq!(graveyard.find(false, PieceType::QUEEN), None);
+        assert_eq!(graveyard.add(false, PieceType::BISHOP), Some(1));
+        assert_eq!(graveyard.add(false, PieceType::BISHOP), Some(3));
+    }
+
+    #[test]
+    fn full_tray() {
+        let mut graveyard = Graveyard::new();
+        for slot in 0..SLOTS {
+            assert_eq!(graveyard.add(true, PieceType::PAWN), Some(slot));
+        }
+        assert_eq!(graveyard.add(true, PieceType::PAWN), None);
+        assert_eq!(graveyard.add(false, PieceType::PAWN), Some(0));
+    }
+
+    #[test]
+    fn slots_fit_next_to_the_board() {
+        let g = Geometry::default();
+        for &white in &[true, false] {
+            for slot in 0..SLOTS {
+                let (x, y) = g.slot_coordinates(white, slot);
+                assert!((g.min_x..=g.max_x).contains(&x));
+                assert!((g.min_y..=g.max_y).contains(&y));
+            }
+        }
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = std::env::temp_dir().join(format!("graveyard-{}.toml", std::process::id()));
+        assert_eq!(Graveyard::load(&path).unwrap(), None);
+
+        let mut graveyard = Graveyard::new();
+        graveyard.add(true, PieceType::KNIGHT);
+        graveyard.add(true, PieceType::PAWN);
+        graveyard.remove(true, 0);
+        graveyard.add(false, PieceType::QUEEN);
+        graveyard.save(&path).unwrap();
+        let loaded = Graveyard::load(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap(), Some(graveyard));
+    }
+}
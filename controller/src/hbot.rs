@@ -1,51 +1,64 @@
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use rust_gpiozero::{DigitalInputDevice, Servo};
 
-use crate::stepper::StepperMotor;
-
-// some constants that can be easily tweaked
-const SLOW_DELAY: f32 = 0.008;
-const FAST_DELAY: f32 = 0.004;
-const MIN_X: i32 = 0;
-const MAX_X: i32 = 2400;
-const MIN_Y: i32 = 0;
-const MAX_Y: i32 = 2200;
-
-fn saftey_delay() {
-    thread::sleep(Duration::new(0, 500_000_000));
+use crate::config::{Config, Geometry, Timing};
+use crate::error::RoboChessError;
+use crate::gantry::Gantry;
+use crate::stepper::{self, Profile, StepperMotor};
+
+/// Distributes the steps of two motors over `max(steps1, steps2)` ticks like Bresenham's line
+/// algorithm. The motor with more steps steps on every tick and the other one as evenly as
+/// possible in between, so the motors always stay on the straight line between start and target.
+fn interleave(steps1: u32, steps2: u32) -> Vec<(bool, bool)> {
+    let n = steps1.max(steps2);
+    let (mut e1, mut e2) = (n / 2, n / 2);
+    (0..n)
+        .map(|_| {
+            e1 += steps1;
+            e2 += steps2;
+            let s1 = e1 >= n;
+            let s2 = e2 >= n;
+            if s1 {
+                e1 -= n;
+            }
+            if s2 {
+                e2 -= n;
+            }
+            (s1, s2)
+        })
+        .collect()
 }
 
-/// Struct to control the two motors and the servo on a high level. The `x` and `y` values are
-/// always initialized correctly because this struct can only be created with `HBot::new`, which
-/// performs the init sequence.
+/// Struct to control the two motors and the servo on a high level. The `x` and `y` values are only
+/// known after the init sequence has succeeded, so the carriage refuses to move from the start of
+/// the init sequence until it completes.
 pub struct HBot {
     m1: StepperMotor,
     m2: StepperMotor,
     b1: DigitalInputDevice,
     b2: DigitalInputDevice,
     s: Servo,
+    geometry: Geometry,
+    timing: Timing,
     x: i32,
     y: i32,
+    /// Whether the init sequence has completed since it was last started, i.e. whether `x` and `y`
+    /// are the real position of the carriage.
+    homed: bool,
 }
 
 impl HBot {
-    /// Creates a new HBot and performs the init sequence.
-    pub fn new(
-        m1_step_pin: u8,
-        m1_dir_pin: u8,
-        m2_step_pin: u8,
-        m2_dir_pin: u8,
-        b1_pin: u8,
-        b2_pin: u8,
-        servo_pin: u8,
-    ) -> Self {
-        let m1 = StepperMotor::new(m1_step_pin, m1_dir_pin);
-        let m2 = StepperMotor::new(m2_step_pin, m2_dir_pin);
-        let b1 = DigitalInputDevice::new(b1_pin);
-        let b2 = DigitalInputDevice::new(b2_pin);
-        let s = Servo::new(servo_pin);
+    /// Creates a new HBot with the pins, bounds and timing from the config and performs the init
+    /// sequence. Fails if the carriage can't be homed.
+    pub fn new(config: &Config) -> Result<Self, RoboChessError> {
+        let pins = &config.pins;
+        let m1 = StepperMotor::new(pins.m1_step, pins.m1_dir);
+        let m2 = StepperMotor::new(pins.m2_step, pins.m2_dir);
+        let b1 = DigitalInputDevice::new(pins.b1);
+        let b2 = DigitalInputDevice::new(pins.b2);
+        let s = Servo::new(pins.servo);
 
         let mut res = Self {
             m1,
@@ -53,104 +66,188 @@ impl HBot {
             b1,
             b2,
             s,
+            geometry: config.geometry.clone(),
+            timing: config.timing.clone(),
             x: 0,
             y: 0,
+            homed: false,
         };
-        res.init_sequence();
-        res
+        res.init_sequence()?;
+        Ok(res)
+    }
+
+    /// Moves to the given coordinates. The steps of both motors are generated from the ramps of
+    /// the given profile by a single loop, so they stay synchronized and the carriage moves on a
+    /// straight line. Fails with [`RoboChessError::Homing`] if the init sequence hasn't completed.
+    pub fn move_to_xy(&mut self, x: i32, y: i32, profile: &Profile) -> Result<(), RoboChessError> {
+        if !self.homed {
+            return Err(RoboChessError::Homing(
+                "the position of the carriage is unknown until it has been homed".to_string(),
+            ));
+        }
+        let g = &self.geometry;
+        if !(g.min_x..=g.max_x).contains(&x) || !(g.min_y..=g.max_y).contains(&y) {
+            return Err(RoboChessError::OutOfBounds { x, y });
+        }
+
+        let dx = x - self.x;
+        let dy = y - self.y;
+
+        let steps1 = -dx - dy;
+        let steps2 = -dx + dy;
+
+        self.m1.set_direction(steps1 > 0);
+        self.m2.set_direction(steps2 > 0);
+        let ticks = interleave(steps1.unsigned_abs(), steps2.unsigned_abs());
+        let times = profile.step_times(ticks.len() as u32);
+        let start = Instant::now();
+        for ((s1, s2), t) in ticks.into_iter().zip(times) {
+            let due = start + Duration::from_secs_f32(t);
+            if let Some(delay) = due.checked_duration_since(Instant::now()) {
+                thread::sleep(delay);
+            }
+            match (s1, s2) {
+                (true, true) => stepper::step(&mut [&mut self.m1, &mut self.m2]),
+                (true, false) => stepper::step(&mut [&mut self.m1]),
+                (false, true) => stepper::step(&mut [&mut self.m2]),
+                (false, false) => {}
+            }
+        }
+        self.x = x;
+        self.y = y;
+        Ok(())
     }
+}
 
-    /// Performs the init sequence
-    pub fn init_sequence(&mut self) {
+impl Gantry for HBot {
+    /// Performs the init sequence. Fails if an endstop stays pressed when the carriage backs off
+    /// or isn't reached within [`Timing::homing_timeout`]. The carriage may have been moved by then,
+    /// so it refuses to move until the init sequence is repeated successfully.
+    fn init_sequence(&mut self) -> Result<(), RoboChessError> {
+        self.homed = false;
+        let delay = self.timing.homing_delay;
+        let timeout = self.timing.homing_timeout;
         self.s.max();
         self.wait();
         println!("touch bottom");
         if self.b1.is_active() {
-            self.m1.turn_steps(SLOW_DELAY, -100);
-            self.m2.turn_steps(SLOW_DELAY, 100);
+            self.m1.turn_steps(delay, -100);
+            self.m2.turn_steps(delay, 100);
             self.m1.wait();
             self.m2.wait();
-            saftey_delay();
+            self.wait();
+            if self.b1.is_active() {
+                return Err(RoboChessError::Hardware(
+                    "the bottom endstop is still pressed after backing off".to_string(),
+                ));
+            }
         }
-        self.m1.turn(true, SLOW_DELAY);
-        self.m2.turn(false, SLOW_DELAY);
-        self.b1.wait_for_active(None);
+        self.m1.turn(true, delay);
+        self.m2.turn(false, delay);
+        self.b1.wait_for_active(Some(timeout));
         self.m1.stop();
         self.m2.stop();
-        self.m1.turn_steps(SLOW_DELAY, -40);
-        self.m2.turn_steps(SLOW_DELAY, 40);
+        if !self.b1.is_active() {
+            return Err(RoboChessError::Homing(format!(
+                "the bottom endstop was not reached within {} s",
+                timeout
+            )));
+        }
+        self.m1.turn_steps(delay, -40);
+        self.m2.turn_steps(delay, 40);
         self.m1.wait();
         self.m2.wait();
         self.m1.stop();
         self.m2.stop();
 
-        saftey_delay();
+        self.wait();
 
         println!("touch right");
         if self.b2.is_active() {
-            self.m1.turn_steps(SLOW_DELAY, -100);
-            self.m2.turn_steps(SLOW_DELAY, -100);
+            self.m1.turn_steps(delay, -100);
+            self.m2.turn_steps(delay, -100);
             self.m1.wait();
             self.m2.wait();
-            saftey_delay();
+            self.wait();
+            if self.b2.is_active() {
+                return Err(RoboChessError::Hardware(
+                    "the right endstop is still pressed after backing off".to_string(),
+                ));
+            }
         }
-        self.m1.turn(true, SLOW_DELAY);
-        self.m2.turn(true, SLOW_DELAY);
-        self.b2.wait_for_active(None);
+        self.m1.turn(true, delay);
+        self.m2.turn(true, delay);
+        self.b2.wait_for_active(Some(timeout));
         self.m1.stop();
         self.m2.stop();
-        self.m1.turn_steps(SLOW_DELAY, -40);
-        self.m2.turn_steps(SLOW_DELAY, -40);
+        if !self.b2.is_active() {
+            return Err(RoboChessError::Homing(format!(
+                "the right endstop was not reached within {} s",
+                timeout
+            )));
+        }
+        self.m1.turn_steps(delay, -40);
+        self.m2.turn_steps(delay, -40);
         self.m1.wait();
         self.m2.wait();
         self.m1.stop();
         self.m2.stop();
 
-        saftey_delay();
+        self.wait();
 
         self.x = 0;
         self.y = 0;
+        self.homed = true;
         println!("init sequence completed");
+        Ok(())
     }
 
-    pub fn move_to_xy(&mut self, x: i32, y: i32, delay: f32) {
-        assert!(MIN_X <= x && x <= MAX_X);
-        assert!(MIN_Y <= y && y <= MAX_Y);
-
-        let dx = x - self.x;
-        let dy = y - self.y;
-
-        let steps1 = -dx - dy;
-        let steps2 = -dx + dy;
-
-        let duration = steps1.abs().max(steps2.abs()) as f32 * delay;
-        self.m1.turn_steps(duration / steps1.abs() as f32, steps1);
-        self.m2.turn_steps(duration / steps2.abs() as f32, steps2);
-        self.m1.wait();
-        self.m2.wait();
-        self.x = x;
-        self.y = y;
-    }
-
-    pub fn move_to_xy_slow(&mut self, x: i32, y: i32) {
-        self.move_to_xy(x, y, SLOW_DELAY);
+    fn move_to_xy_slow(&mut self, x: i32, y: i32) -> Result<(), RoboChessError> {
+        let profile = self.timing.slow;
+        self.move_to_xy(x, y, &profile)
     }
 
-    pub fn move_to_xy_fast(&mut self, x: i32, y: i32) {
-        self.move_to_xy(x, y, FAST_DELAY);
+    fn move_to_xy_fast(&mut self, x: i32, y: i32) -> Result<(), RoboChessError> {
+        let profile = self.timing.fast;
+        self.move_to_xy(x, y, &profile)
     }
 
-    pub fn up(&mut self) {
+    fn up(&mut self) {
         self.s.min();
         self.wait();
     }
 
-    pub fn down(&mut self) {
+    fn down(&mut self) {
         self.s.max();
         self.wait();
     }
 
-    pub fn wait(&self) {
-        saftey_delay();
+    fn position(&self) -> (i32, i32) {
+        (self.x, self.y)
+    }
+
+    fn wait(&self) {
+        thread::sleep(Duration::from_secs_f32(self.timing.settle_time));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interleave_keeps_the_line_straight() {
+        let ticks = interleave(10, 4);
+        assert_eq!(ticks.len(), 10);
+        assert!(ticks.iter().all(|&(s1, _)| s1));
+        assert_eq!(ticks.iter().filter(|&&(_, s2)| s2).count(), 4);
+
+        // after every tick the second motor is less than one step away from the ideal line
+        let mut done = 0;
+        for (i, &(_, s2)) in ticks.iter().enumerate() {
+            done += s2 as i32;
+            let ideal = (i + 1) as f32 * 4.0 / 10.0;
+            assert!((done as f32 - ideal).abs() < 1.0);
+        }
     }
 }
@@ -0,0 +1,406 @@
+//! The journal of the game. It is saved after every completed move and whenever a position has
+//! been set up, so that the game can be resumed after the controller has been restarted, e.g. after
+//! a power failure. The board still holds the pieces of that game, so without the journal the
+//! controller would start from the start position and disagree with the board.
+//!
+//! Before the robot starts to carry out a move or to arrange the pieces, the journal is saved with
+//! that [`Task`]. If the journal still names a task after a restart, the robot was interrupted in
+//! the middle of it and the pieces may not stand where the journal says.
+//!
+//! A move is carried out as a list of [`Carry`]s, each carrying one piece. Every sub-step of a
+//! carry is journaled before it starts ([`Phase`]) and once the piece has been put down, so after
+//! an interruption it is known which pieces have reached their destination and which one was on
+//! its way. The move can then be rolled forward or back, cf.
+//! [`crate::robochess::RoboChess::recover`]. The magnet is lowered when the robot starts, so a
+//! piece that was being carried stays where the carriage stopped and has to be put on its origin
+//! or destination by hand.
+//!
+//! The journal is written the way [`crate::persist`] describes, so that it is never left
+//! half-written.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use chers::Position;
+use serde::{Deserialize, Serialize};
+
+use crate::arrange::piece_name;
+use crate::graveyard::Trays;
+use crate::persist;
+
+/// Carrying a piece: the magnet picks it up at `start`, carries it along `path` and puts it down at
+/// the last point of the path.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Carry {
+    /// The letter of the piece like in a FEN, upper case for white.
+    pub piece: char,
+    /// Where the piece is picked up and put down, e.g. `e2`, for the players.
+    pub from: String,
+    pub to: String,
+    pub start: (i32, i32),
+    pub path: Vec<(i32, i32)>,
+    /// Whether the piece is given time to settle after every point of the path.
+    pub settle: bool,
+}
+
+impl Carry {
+    /// Returns the carry that takes the piece back along the same path.
+    pub fn reversed(&self) -> Self {
+        let mut path: Vec<(i32, i32)> = self.path.iter().rev().skip(1).copied().collect();
+        path.push(self.start);
+        Self {
+            piece: self.piece,
+            from: self.to.clone(),
+            to: self.from.clone(),
+            start: *self.path.last().unwrap_or(&self.start),
+            path,
+            settle: self.settle,
+        }
+    }
+}
+
+/// A sub-step of a [`Carry`].
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    /// Moving to the piece and picking it up. The piece still stands at its origin.
+    Pick,
+    /// Carrying the piece, which may stand anywhere on the path.
+    Carry,
+    /// Putting the piece down. It already stands at its destination.
+    Place,
+}
+
+/// How far the robot got with the pieces of a move.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Progress {
+    /// The move in coordinate notation.
+    pub m: String,
+    pub carries: Vec<Carry>,
+    /// The number of carries whose piece stands at its destination.
+    pub done: usize,
+    /// The sub-step of the carry in progress: the next one or, if `back` is set, the last one done,
+    /// which is carried back.
+    pub phase: Option<Phase>,
+    /// Whether the move is rolled back.
+    pub back: bool,
+}
+
+impl Progress {
+    /// Returns the number of carries whose piece stands at its destination and the carry whose
+    /// piece stands somewhere on its path, if any.
+    pub fn settled(&self) -> (usize, Option<usize>) {
+        match (self.phase, self.back) {
+            (None, _) | (Some(Phase::Pick), _) => (self.done, None),
+            (Some(Phase::Carry), false) => (self.done, Some(self.done)),
+            (Some(Phase::Place), false) => (self.done + 1, None),
+            (Some(Phase::Carry), true) => (self.done - 1, Some(self.done - 1)),
+            (Some(Phase::Place), true) => (self.done - 1, None),
+        }
+    }
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let verb = if self.back { "taking back" } else { "playing" };
+        let (done, on_the_way) = self.settled();
+        write!(
+            f,
+            "{} {} with {} of {} pieces at their destination",
+            verb,
+            self.m,
+            done,
+            self.carries.len()
+        )?;
+        let i = match on_the_way {
+            Some(i) => i,
+            None => return Ok(()),
+        };
+        let carry = &self.carries[i];
+        write!(
+            f,
+            ", the {} was being carried from {} to {}: put it on {} to roll the move forward or on \
+             {} to roll it back",
+            piece_name(carry.piece),
+            carry.from,
+            carry.to,
+            carry.to,
+            carry.from
+        )
+    }
+}
+
+/// What the robot is doing while the journal is saved.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Task {
+    /// Carrying out a move.
+    Move(Progress),
+    /// Arranging the pieces for the position given as a complete FEN.
+    Arrangement(String),
+}
+
+impl fmt::Display for Task {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Task::Move(progress) => write!(f, "{}", progress),
+            Task::Arrangement(fen) => write!(f, "arranging the pieces for {}", fen),
+        }
+    }
+}
+
+/// How an interrupted move is finished.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Roll {
+    /// Carries the remaining pieces, so that the move is made.
+    Forward,
+    /// Carries the pieces that have been moved back, so that the move is not made.
+    Back,
+}
+
+/// The state of the game, cf. [`crate::robochess::RoboChess::journal`]. Squares are saved like
+/// `e4` and pieces as their letter.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Journal {
+    /// The complete FEN of the current position.
+    pub fen: String,
+    /// When the position was set up, in seconds since the unix epoch.
+    pub started: u64,
+    pub reserve_white_used: bool,
+    pub reserve_black_used: bool,
+    /// The square a human has to put a promoted piece on.
+    pub awaited_piece: Option<String>,
+    /// The coordinates of the carriage.
+    pub carriage: (i32, i32),
+    /// The move the robot is carrying out, cf. [`Task::Move`] and [`Progress`].
+    pub moving: Option<String>,
+    #[serde(default)]
+    pub done: usize,
+    pub phase: Option<Phase>,
+    #[serde(default)]
+    pub back: bool,
+    /// The position the pieces are arranged for, cf. [`Task::Arrangement`].
+    pub arranging: Option<String>,
+    /// The squares of the pieces that stand in for promoted pieces and their types.
+    pub substitutes: Vec<(String, String)>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub carries: Vec<Carry>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<Entry>,
+    pub graveyard: Trays,
+}
+
+/// A move that has been made, cf. [`crate::robochess::Played`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Entry {
+    /// The move in coordinate notation.
+    #[serde(rename = "move")]
+    pub m: String,
+    pub san: String,
+    pub time: u64,
+    /// The complete FEN of the position before the move.
+    pub fen: String,
+    /// The slot of the promoted pawn.
+    pub pawn: Option<usize>,
+    /// Where the spare queen was taken from if the pawn was promoted to it.
+    pub reserve: Option<(i32, i32)>,
+    pub substitutes: Vec<(String, String)>,
+    pub captured: Option<Captured>,
+}
+
+/// The slot of a captured piece and its physical type.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Captured {
+    pub slot: usize,
+    pub piece: String,
+}
+
+#[derive(Debug)]
+pub enum JournalError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+}
+
+impl fmt::Display for JournalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JournalError::Io(e) => write!(f, "{}", e),
+            JournalError::Parse(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Journal {
+    /// Returns the task the robot was interrupted in, if any.
+    pub fn task(&self) -> Option<Task> {
+        match (&self.moving, &self.arranging) {
+            (Some(m), _) => Some(Task::Move(Progress {
+                m: m.clone(),
+                carries: self.carries.clone(),
+                done: self.done,
+                phase: self.phase,
+                back: self.back,
+            })),
+            (None, Some(fen)) => Some(Task::Arrangement(fen.clone())),
+            (None, None) => None,
+        }
+    }
+
+    /// Returns whether the journal holds nothing worth resuming, i.e. the start position without
+    /// any moves.
+    pub fn is_new_game(&self) -> bool {
+        self.history.is_empty() && self.task().is_none() && self.fen == Position::new().to_fen()
+    }
+
+    /// Loads a journal saved with [`Journal::save`]. Returns `None` if the file doesn't exist.
+    pub fn load(path: &Path) -> Result<Option<Self>, JournalError> {
+        match fs::read_to_string(path) {
+            Ok(content) => toml::from_str(&content)
+                .map(Some)
+                .map_err(JournalError::Parse),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(JournalError::Io(e)),
+        }
+    }
+
+    /// Replaces the journal at `path` with this one.
+    pub fn save(&self, path: &Path) -> Result<(), JournalError> {
+        let content = toml::to_string(self).expect("a journal can always be serialized");
+        persist::write_atomically(path, &content).map_err(JournalError::Io)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::graveyard::Graveyard;
+
+    #[test]
+    fn save_and_load() {
+        let path = std::env::temp_dir().join(format!("journal-{}.toml", std::process::id()));
+        assert_eq!(Journal::load(&path).unwrap(), None);
+
+        let mut graveyard = Graveyard::new();
+        graveyard.add(true, chers::PieceType::PAWN);
+        let journal = Journal {
+            fen: "rnbqkbnr/ppp1pppp/8/3P4/8/8/PPPP1PPP/RNBQKBNR b KQkq - 0 2".to_string(),
+            started: 1_600_000_000,
+            reserve_white_used: false,
+            reserve_black_used: true,
+            awaited_piece: None,
+            carriage: (120, 2000),
+            moving: Some("d8d5".to_string()),
+            done: 1,
+            phase: Some(Phase::Place),
+            back: false,
+            arranging: None,
+            substitutes: vec![("a8".to_string(), "r".to_string())],
+            carries: carries(),
+            history: vec![
+                Entry {
+                    m: "e2e4".to_string(),
+                    san: "e4".to_string(),
+                    time: 1_600_000_010,
+                    fen: Position::new().to_fen(),
+                    pawn: None,
+                    reserve: None,
+                    substitutes: Vec::new(),
+                    captured: None,
+                },
+                Entry {
+                    m: "e4d5".to_string(),
+                    san: "exd5".to_string(),
+                    time: 1_600_000_030,
+                    fen: "rnbqkbnr/ppp1pppp/8/3p4/4P3/8/PPPP1PPP/RNBQKBNR w KQkq d6 0 2"
+                        .to_string(),
+                    pawn: None,
+                    reserve: None,
+                    substitutes: vec![("a8".to_string(), "r".to_string())],
+                    captured: Some(Captured {
+                        slot: 0,
+                        piece: "p".to_string(),
+                    }),
+                },
+            ],
+            graveyard: graveyard.to_trays(),
+        };
+        journal.save(&path).unwrap();
+        let loaded = Journal::load(&path);
+        fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap().unwrap();
+        assert_eq!(loaded, journal);
+        let progress = Progress {
+            m: "d8d5".to_string(),
+            carries: carries(),
+            done: 1,
+            phase: Some(Phase::Place),
+            back: false,
+        };
+        assert_eq!(loaded.task(), Some(Task::Move(progress)));
+        assert!(!loaded.is_new_game());
+    }
+
+    #[test]
+    fn settled() {
+        let mut progress = Progress {
+            m: "d8d5".to_string(),
+            carries: carries(),
+            done: 1,
+            phase: None,
+            back: false,
+        };
+        assert_eq!(progress.settled(), (1, None));
+        progress.phase = Some(Phase::Carry);
+        assert_eq!(progress.settled(), (1, Some(1)));
+        assert_eq!(
+            progress.to_string(),
+            "playing d8d5 with 1 of 2 pieces at their destination, the black queen was being \
+             carried from d8 to d5: put it on d5 to roll the move forward or on d8 to roll it back"
+        );
+        progress.phase = Some(Phase::Place);
+        assert_eq!(progress.settled(), (2, None));
+        progress.back = true;
+        assert_eq!(progress.settled(), (0, None));
+        progress.phase = Some(Phase::Carry);
+        assert_eq!(progress.settled(), (0, Some(0)));
+    }
+
+    #[test]
+    fn reversed() {
+        let carry = &carries()[1];
+        let back = carry.reversed();
+        assert_eq!((back.from.as_str(), back.to.as_str()), ("d5", "d8"));
+        assert_eq!(back.start, (1150, 1995));
+        assert_eq!(back.path, vec![(1150, 1700), carry.start]);
+        assert_eq!(back.reversed(), *carry);
+    }
+
+    /// The carries of the capture d8d5.
+    fn carries() -> Vec<Carry> {
+        vec![
+            Carry {
+                piece: 'P',
+                from: "d5".to_string(),
+                to: "slot 1 of the white tray".to_string(),
+                start: (1150, 1500),
+                path: vec![(1150, 1600), (2300, 1600), (2300, 100)],
+                settle: false,
+            },
+            Carry {
+                piece: 'q',
+                from: "d8".to_string(),
+                to: "d5".to_string(),
+                start: (1150, 2400),
+                path: vec![(1150, 1700), (1150, 1995)],
+                settle: false,
+            },
+        ]
+    }
+}
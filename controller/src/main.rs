@@ -6,134 +6,158 @@
 //!  some of its functionality. It's not really idomatic and there are some bugs
 //!  which require workarounds (cf. [stepper::StepperMotor::turn_steps]). But it works and it's
 //!  more high level than the alternatives.
+//!
+//!  When started with `--simulate` the controller uses a [`simulator::SimulatedGantry`] instead of
+//!  the real hardware and prints every motion command, so it can also be run on a normal computer.
+//!
+//!  The pins, the geometry of the board and the timing are read from `robochess.toml` or the file
+//!  given with `--config <path>`. Without a config file the defaults from [`config::Config`] are
+//!  used.
+//!
+//!  The controller keeps the calibration, the captured pieces in the trays and the journal of the
+//!  game in files, cf. [`persist`]. A missing file means that there is nothing to restore, but a
+//!  file that can't be read stops the controller at the start like a broken config. Otherwise it
+//!  would be overwritten with a state that doesn't match the pieces on the board. Such a file has
+//!  to be repaired or deleted by hand.
+//!
+//!  The web interface and other clients connect over a websocket and speak one of the protocols
+//!  described in [`protocol`], cf. [`server`].
 
+mod arrange;
+mod calibration;
+mod config;
+mod engine;
+mod error;
+mod gantry;
+mod graveyard;
 mod hbot;
+mod journal;
+mod persist;
+mod pgn;
+mod planner;
+mod protocol;
 mod robochess;
+mod server;
+mod simulator;
 mod stepper;
 
-use websocket::sync::Server;
-use websocket::OwnedMessage;
-
-use chers::{Color, ParsedMove, Position};
+use std::env;
+use std::path::Path;
+use std::process;
 
+use calibration::Transform;
+use config::Config;
+use graveyard::Graveyard;
+use journal::Journal;
 use robochess::RoboChess;
+use server::serve;
+use simulator::SimulatedGantry;
 
-const SUB_PROTOCOL: &'static str = "robochess-websocket";
-
-fn main() {
-    let mut controller = RoboChess::new();
-    // controller.position =
-    //     Position::from_fen("r3k2r/pp1bqppp/2pp1n2/4p3/1bBPPB1N/2N5/PPP1QPPP/R3K2R b KQkq - 6 13")
-    //         .unwrap();
-    // controller.make_move(ParsedMove::from_coordinate_notation("e8c8").unwrap());
-    // return;
-    let server = Server::bind("0.0.0.0:8080").unwrap();
+const DEFAULT_CONFIG: &str = "robochess.toml";
 
-    'outer: for request in server.filter_map(Result::ok) {
-        if !request.protocols().contains(&SUB_PROTOCOL.to_string()) {
-            println!("Invalid subprotocols: {:?}", request.protocols());
-            request.reject().unwrap();
-            return;
+/// Loads the config from the path given with `--config` or from [`DEFAULT_CONFIG`] if it exists.
+fn load_config(args: &[String]) -> Config {
+    let path = match args.iter().position(|arg| arg == "--config") {
+        Some(i) => match args.get(i + 1) {
+            Some(path) => Path::new(path),
+            None => {
+                eprintln!("--config requires a path");
+                process::exit(1);
+            }
+        },
+        None if Path::new(DEFAULT_CONFIG).exists() => Path::new(DEFAULT_CONFIG),
+        None => {
+            println!("No config file found, using the defaults");
+            return Config::default();
         }
-
-        let client = request.use_protocol(SUB_PROTOCOL).accept().unwrap();
-
-        let ip = client.peer_addr().unwrap();
-
-        println!("Connection from {}", ip);
-
-        let (mut receiver, mut sender) = client.split().unwrap();
-
-        let message = OwnedMessage::Text(format!("!set {}", controller.position.to_fen()));
-        if sender.send_message(&message).is_err() {
-            continue 'outer;
+    };
+    match Config::load(path) {
+        Ok(config) => {
+            println!("Loaded config from {}", path.display());
+            config
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
         }
+    }
+}
 
-        for message in receiver.incoming_messages() {
-            if let Ok(message) = message {
-                match message {
-                    OwnedMessage::Close(_) => {
-                        let message = OwnedMessage::Close(None);
-                        if sender.send_message(&message).is_err() {
-                            continue 'outer;
-                        }
-                        println!("Client {} disconnected", ip);
-                        continue 'outer;
-                    }
-                    OwnedMessage::Ping(ping) => {
-                        let message = OwnedMessage::Pong(ping);
-                        println!("pong!");
-                        if sender.send_message(&message).is_err() {
-                            continue 'outer;
-                        }
-                    }
-                    OwnedMessage::Text(data) => {
-                        if data.starts_with("!") {
-                            match data.as_str() {
-                                "!calibrate" => {
-                                    controller.controller.init_sequence();
-                                }
-                                s if s.starts_with("!fen") => {
-                                    if let Ok(pos) = Position::from_fen(&s[5..]) {
-                                        controller.position = pos;
-                                        controller.captured_pieces_white.clear();
-                                        controller.captured_pieces_black.clear();
-                                    }
+/// Loads the result of the last board calibration, if there is one.
+fn load_calibration(config: &mut Config) {
+    let path = &config.geometry.calibration_file;
+    match Transform::load(path) {
+        Ok(Some(transform)) => {
+            println!("Loaded calibration from {}", path.display());
+            config.geometry.calibration = Some(transform);
+        }
+        Ok(None) => println!("The board has not been calibrated yet"),
+        Err(e) => {
+            eprintln!("could not load {}: {}", path.display(), e);
+            process::exit(1);
+        }
+    }
+}
 
-                                    let message = OwnedMessage::Text(format!(
-                                        "!set {}",
-                                        controller.position.to_fen()
-                                    ));
-                                    if sender.send_message(&message).is_err() {
-                                        continue 'outer;
-                                    }
-                                    let message = if controller.position.is_checkmate() {
-                                        OwnedMessage::Text("!checkmate".to_string())
-                                    } else if controller.position.is_draw() {
-                                        OwnedMessage::Text("!draw".to_string())
-                                    } else if controller.position.side_to_move() == Color::WHITE {
-                                        OwnedMessage::Text("!white".to_string())
-                                    } else {
-                                        OwnedMessage::Text("!black".to_string())
-                                    };
-                                    if sender.send_message(&message).is_err() {
-                                        continue 'outer;
-                                    }
-                                }
-                                _ => println!("Invalid command {}", data),
-                            }
-                        } else {
-                            if let Ok(m) = ParsedMove::from_coordinate_notation(&data) {
-                                if !controller.make_move(m) {
-                                    let message = OwnedMessage::Text(format!(
-                                        "!set {}",
-                                        controller.position.to_fen()
-                                    ));
-                                    if sender.send_message(&message).is_err() {
-                                        continue 'outer;
-                                    }
-                                }
+/// Loads where the captured pieces were left, if the robot has been running before.
+fn load_graveyard(config: &Config) -> Graveyard {
+    let path = &config.geometry.graveyard_file;
+    match Graveyard::load(path) {
+        Ok(Some(graveyard)) => {
+            println!("Loaded the captured pieces from {}", path.display());
+            graveyard
+        }
+        Ok(None) => Graveyard::new(),
+        Err(e) => {
+            eprintln!("could not load {}: {}", path.display(), e);
+            process::exit(1);
+        }
+    }
+}
 
-                                let message = if controller.position.is_checkmate() {
-                                    OwnedMessage::Text("!checkmate".to_string())
-                                } else if controller.position.is_draw() {
-                                    OwnedMessage::Text("!draw".to_string())
-                                } else if controller.position.side_to_move() == Color::WHITE {
-                                    OwnedMessage::Text("!white".to_string())
-                                } else {
-                                    OwnedMessage::Text("!black".to_string())
-                                };
-                                if sender.send_message(&message).is_err() {
-                                    continue 'outer;
-                                }
-                            }
-                        }
-                    }
-                    _ => {
-                        println!("Unexpected message: {:?}", message);
-                    }
-                }
+/// Loads the game that was played before the restart, unless there is nothing to resume.
+fn load_journal(config: &Config) -> Option<Journal> {
+    let path = &config.geometry.journal_file;
+    match Journal::load(path) {
+        Ok(Some(journal)) if journal.is_new_game() => None,
+        Ok(Some(journal)) => {
+            println!(
+                "Loaded a game with {} moves from {}",
+                journal.history.len(),
+                path.display()
+            );
+            if let Some(task) = journal.task() {
+                println!("The robot was interrupted while {}", task);
             }
+            Some(journal)
+        }
+        Ok(None) => None,
+        Err(e) => {
+            eprintln!("could not load {}: {}", path.display(), e);
+            process::exit(1);
         }
     }
 }
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let mut config = load_config(&args);
+    load_calibration(&mut config);
+    let graveyard = load_graveyard(&config);
+    let saved = load_journal(&config);
+    let result = if args.iter().any(|arg| arg == "--simulate") {
+        let gantry = SimulatedGantry::new(&config.geometry, true);
+        let mut robochess = RoboChess::with_config(gantry, &config);
+        robochess.graveyard = graveyard;
+        serve(robochess, &config, saved)
+    } else {
+        RoboChess::new(&config).and_then(|mut robochess| {
+            robochess.graveyard = graveyard;
+            serve(robochess, &config, saved)
+        })
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,35 @@
+//! Writing the files the controller keeps its state in: the journal, the occupied slots of the
+//! trays and the calibration. They are rewritten while the robot is running, so the power may fail
+//! in the middle of a write. A file is therefore written to a temporary file next to it first,
+//! which then replaces it, so that it is never left half-written.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Replaces the content of the file at `path`. The old content stays if writing fails.
+pub fn write_atomically(path: &Path, content: &str) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(content.as_bytes())?;
+    // the new content has to be on the disk before it replaces the old one
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replace_content() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("persist-{}.toml", std::process::id()));
+        write_atomically(&path, "a = 1\n").unwrap();
+        write_atomically(&path, "a = 2\n").unwrap();
+        let content = fs::read_to_string(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(content.unwrap(), "a = 2\n");
+        assert!(!path.with_extension("tmp").exists());
+    }
+}
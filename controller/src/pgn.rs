@@ -0,0 +1,431 @@
+//! Recording games in the portable game notation. Every game is written as a PGN with the moves in
+//! standard algebraic notation (cf. [`notation::san`]) and the time each move took as an
+//! `[%emt h:mm:ss]` comment. PGNs of other games can be read to replay them on the board.
+
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chers::{BitMove, PieceType, Position};
+
+const START: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+/// Returns the current time in seconds since the unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn letter(piece: PieceType) -> String {
+    piece.to_string().to_ascii_uppercase()
+}
+
+/// Finds the legal move written in standard algebraic notation. Missing or superfluous check
+/// signs, annotations like `!?` and long algebraic notation like `Ng1-f3` are accepted.
+pub fn parse_san(position: &Position, token: &str) -> Result<BitMove, String> {
+    let invalid = || format!("invalid move {}", token);
+    let s = token
+        .trim_end_matches(|c| "+#!?".contains(c))
+        .trim_end_matches("e.p.")
+        .replace('0', "O");
+    let moves = position.generate_legal_moves();
+    if s == "O-O" || s == "O-O-O" {
+        return moves
+            .into_iter()
+            .find(|m| m.is_castle() && m.is_king_side_castle() == (s == "O-O"))
+            .ok_or_else(|| format!("illegal move {}", token));
+    }
+
+    let mut chars: Vec<char> = s.chars().filter(|&c| c != 'x' && c != '-').collect();
+    let piece = match chars.first() {
+        Some(c) if "KQRBN".contains(*c) => chars.remove(0),
+        _ => 'P',
+    };
+    let promotion = match chars.last() {
+        Some(&c) if piece == 'P' && "QRBN".contains(c) => {
+            chars.pop();
+            if chars.last() == Some(&'=') {
+                chars.pop();
+            }
+            Some(c)
+        }
+        _ => None,
+    };
+    if chars.len() < 2 {
+        return Err(invalid());
+    }
+    let target: String = chars.split_off(chars.len() - 2).into_iter().collect();
+    if !chars
+        .iter()
+        .all(|c| ('a'..='h').contains(c) || ('1'..='8').contains(c))
+    {
+        return Err(invalid());
+    }
+
+    let candidates: Vec<BitMove> = moves
+        .into_iter()
+        .filter(|m| {
+            let origin = m.origin().to_string();
+            let promoted = if m.is_promotion() {
+                letter(m.promotion_piece()).chars().next()
+            } else {
+                None
+            };
+            !m.is_castle()
+                && m.target().to_string() == target
+                && letter(position.get_square(m.origin()).piece_type()) == piece.to_string()
+                && chars.iter().all(|&c| origin.contains(c))
+                && promoted == promotion
+        })
+        .collect();
+    match candidates.as_slice() {
+        [m] => Ok(*m),
+        [] => Err(format!("illegal move {}", token)),
+        _ => Err(format!("ambiguous move {}", token)),
+    }
+}
+
+/// A game as it is written to a PGN.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub white: String,
+    pub black: String,
+    /// The complete FEN of the start position.
+    pub fen: String,
+    /// When the game started, in seconds since the unix epoch.
+    pub started: u64,
+    /// The moves in standard algebraic notation with the time they were made.
+    pub moves: Vec<(String, u64)>,
+    /// `1-0`, `0-1`, `1/2-1/2` or `*` if the game isn't over.
+    pub result: &'static str,
+}
+
+/// Converts seconds since the unix epoch to the date and the time in UTC.
+fn date_time(secs: u64) -> ((i64, u32, u32), (u64, u64, u64)) {
+    let days = (secs / 86400) as i64;
+    let secs = secs % 86400;
+    // cf. http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    ((year, month, day), (secs / 3600, secs / 60 % 60, secs % 60))
+}
+
+impl Record {
+    /// The name of the file the game is saved to, which is derived from its start.
+    pub fn file_name(&self) -> String {
+        let ((year, month, day), (h, m, s)) = date_time(self.started);
+        format!(
+            "{:04}-{:02}-{:02}-{:02}{:02}{:02}.pgn",
+            year, month, day, h, m, s
+        )
+    }
+
+    pub fn to_pgn(&self) -> String {
+        let ((year, month, day), (h, m, s)) = date_time(self.started);
+        let escape = |s: &str| s.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut pgn = String::new();
+        let mut tags = vec![
+            ("Event", "Casual game".to_string()),
+            ("Site", "RoboChess".to_string()),
+            ("Date", format!("{:04}.{:02}.{:02}", year, month, day)),
+            ("Round", "-".to_string()),
+            ("White", escape(&self.white)),
+            ("Black", escape(&self.black)),
+            ("Result", self.result.to_string()),
+            ("UTCTime", format!("{:02}:{:02}:{:02}", h, m, s)),
+        ];
+        if self.fen != START {
+            tags.push(("SetUp", "1".to_string()));
+            tags.push(("FEN", self.fen.clone()));
+        }
+        for (name, value) in tags {
+            writeln!(pgn, "[{} \"{}\"]", name, value).unwrap();
+        }
+        pgn.push('\n');
+
+        let mut fields = self.fen.split(' ').skip(1);
+        let mut white = fields.next() != Some("b");
+        let mut number: u64 = fields.nth(3).and_then(|n| n.parse().ok()).unwrap_or(1);
+        let mut tokens = Vec::new();
+        let mut last = self.started;
+        for (i, (san, time)) in self.moves.iter().enumerate() {
+            if white {
+                tokens.push(format!("{}.", number));
+            } else if i == 0 {
+                tokens.push(format!("{}...", number));
+            }
+            tokens.push(san.clone());
+            let emt = time.saturating_sub(last);
+            tokens.push(format!(
+                "{{[%emt {}:{:02}:{:02}]}}",
+                emt / 3600,
+                emt / 60 % 60,
+                emt % 60
+            ));
+            last = *time;
+            if !white {
+                number += 1;
+            }
+            white = !white;
+        }
+        tokens.push(self.result.to_string());
+
+        // the lines of the movetext must not be longer than 80 characters
+        let mut line = String::new();
+        for token in tokens {
+            if !line.is_empty() && line.len() + 1 + token.len() > 80 {
+                pgn += &line;
+                pgn.push('\n');
+                line.clear();
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line += &token;
+        }
+        pgn += &line;
+        pgn.push('\n');
+        pgn
+    }
+}
+
+/// A game read from a PGN.
+#[derive(Debug)]
+pub struct Imported {
+    pub white: String,
+    pub black: String,
+    /// The complete FEN of the start position.
+    pub fen: String,
+    pub moves: Vec<BitMove>,
+}
+
+/// Removes the comments, variations and annotation glyphs from a movetext.
+fn strip_movetext(movetext: &str) -> Result<String, String> {
+    let mut res = String::new();
+    let mut depth = 0;
+    let mut chars = movetext.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' => {
+                if !chars.any(|c| c == '}') {
+                    return Err("unterminated comment".to_string());
+                }
+                res.push(' ');
+            }
+            ';' => {
+                chars.any(|c| c == '\n');
+                res.push(' ');
+            }
+            '(' => depth += 1,
+            ')' if depth == 0 => return Err("unbalanced variation".to_string()),
+            ')' => {
+                depth -= 1;
+                res.push(' ');
+            }
+            _ if depth > 0 => {}
+            _ => res.push(c),
+        }
+    }
+    if depth > 0 {
+        return Err("unterminated variation".to_string());
+    }
+    Ok(res)
+}
+
+/// Reads the first game of a PGN and checks that all of its moves are legal.
+pub fn parse(pgn: &str) -> Result<Imported, String> {
+    let mut tags = Vec::new();
+    let mut movetext = String::new();
+    for line in pgn.lines().map(str::trim) {
+        if line.starts_with('%') {
+            continue;
+        }
+        if let Some(tag) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            if !movetext.trim().is_empty() {
+                // the tags of the next game
+                break;
+            }
+            let (name, value) = tag
+                .split_once(' ')
+                .ok_or_else(|| format!("invalid tag {}", line))?;
+            let value = value
+                .trim()
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .ok_or_else(|| format!("invalid tag {}", line))?;
+            tags.push((
+                name.to_string(),
+                value.replace("\\\"", "\"").replace("\\\\", "\\"),
+            ));
+        } else {
+            movetext += line;
+            movetext.push('\n');
+        }
+    }
+    let tag = |name: &str| {
+        tags.iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.clone())
+    };
+
+    let fen = tag("FEN").unwrap_or_else(|| START.to_string());
+    let mut position = Position::from_fen(&fen).map_err(|_| format!("invalid fen {}", fen))?;
+    let fen = position.to_fen();
+    let mut moves = Vec::new();
+    for token in strip_movetext(&movetext)?.split_whitespace() {
+        if ["1-0", "0-1", "1/2-1/2", "*"].contains(&token) {
+            break;
+        }
+        // move numbers may be written without a space, e.g. `1.e4`
+        let token = match token.rfind('.') {
+            Some(i) if token[..i].chars().all(|c| c.is_ascii_digit() || c == '.') => {
+                &token[i + 1..]
+            }
+            _ => token,
+        };
+        if token.is_empty() || token.starts_with('$') {
+            continue;
+        }
+        let m = parse_san(&position, token)
+            .map_err(|e| format!("{} after {} moves", e, moves.len()))?;
+        position.make_bit_move(m);
+        moves.push(m);
+    }
+    Ok(Imported {
+        white: tag("White").unwrap_or_else(|| "?".to_string()),
+        black: tag("Black").unwrap_or_else(|| "?".to_string()),
+        fen,
+        moves,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chers::Square;
+    use notation::san;
+
+    fn san_of(fen: &str, m: &str) -> String {
+        let position = Position::from_fen(fen).unwrap();
+        let m = parse_san(&position, m).unwrap();
+        san(&position, m)
+    }
+
+    #[test]
+    fn standard_algebraic_notation() {
+        assert_eq!(san_of(START, "e2e4"), "e4");
+        assert_eq!(san_of(START, "Ng1-f3"), "Nf3");
+        // both knights can reach d2
+        let fen = "rnbqkbnr/pppppppp/8/8/8/5N2/PPP1PPPP/RNBQKB1R w KQkq - 0 1";
+        assert_eq!(san_of(fen, "Nbd2"), "Nbd2");
+        let fen = "rnbqkbnr/ppp1pppp/8/3p4/4P3/8/PPPP1PPP/RNBQKBNR w KQkq d6 0 2";
+        assert_eq!(san_of(fen, "exd5"), "exd5");
+        let fen = "5k2/8/8/8/8/8/8/R3K2R w KQ - 0 1";
+        assert_eq!(san_of(fen, "0-0"), "O-O+");
+        assert_eq!(san_of(fen, "O-O-O"), "O-O-O");
+        assert_eq!(san_of(fen, "Ra8"), "Ra8+");
+        let fen = "8/4P3/8/8/8/8/k7/4K3 w - - 0 1";
+        assert_eq!(san_of(fen, "e8N"), "e8=N");
+        assert_eq!(san_of(fen, "e8=Q+"), "e8=Q");
+    }
+
+    #[test]
+    fn invalid_moves() {
+        let position = Position::new();
+        assert_eq!(
+            parse_san(&position, "e5"),
+            Err("illegal move e5".to_string())
+        );
+        assert_eq!(
+            parse_san(&position, "Zz9"),
+            Err("invalid move Zz9".to_string())
+        );
+        let fen = "rnbqkbnr/pppppppp/8/8/8/5N2/PPP1PPPP/RNBQKB1R w KQkq - 0 1";
+        let position = Position::from_fen(fen).unwrap();
+        assert_eq!(
+            parse_san(&position, "Nd2"),
+            Err("ambiguous move Nd2".to_string())
+        );
+    }
+
+    #[test]
+    fn write_pgn() {
+        let record = Record {
+            white: "Human".to_string(),
+            black: "RoboChess (depth 3)".to_string(),
+            fen: START.to_string(),
+            // 2026-10-18 14:03:05 UTC
+            started: 1_792_332_185,
+            moves: vec![
+                ("e4".to_string(), 1_792_332_190),
+                ("e5".to_string(), 1_792_332_191),
+                ("Qh5".to_string(), 1_792_335_791),
+            ],
+            result: "*",
+        };
+        assert_eq!(record.file_name(), "2026-10-18-140305.pgn");
+        assert_eq!(
+            record.to_pgn(),
+            "[Event \"Casual game\"]\n\
+             [Site \"RoboChess\"]\n\
+             [Date \"2026.10.18\"]\n\
+             [Round \"-\"]\n\
+             [White \"Human\"]\n\
+             [Black \"RoboChess (depth 3)\"]\n\
+             [Result \"*\"]\n\
+             [UTCTime \"14:03:05\"]\n\
+             \n\
+             1. e4 {[%emt 0:00:05]} e5 {[%emt 0:00:01]} 2. Qh5 {[%emt 1:00:00]} *\n"
+        );
+    }
+
+    #[test]
+    fn read_pgn() {
+        let pgn = "[Event \"Casual game\"]\n\
+                   [White \"Kasparov, \\\"Garry\\\"\"]\n\
+                   \n\
+                   1.e4 {best by test} e5 (1... c5 2. Nf3) 2. Nf3 $1 Nc6 ; the main line\n\
+                   3. Bb5 a6 1-0\n\
+                   \n\
+                   [Event \"Next game\"]\n\
+                   \n\
+                   1. d4 *\n";
+        let imported = parse(pgn).unwrap();
+        assert_eq!(imported.white, "Kasparov, \"Garry\"");
+        assert_eq!(imported.black, "?");
+        assert_eq!(imported.fen, START);
+        assert_eq!(imported.moves.len(), 6);
+        assert_eq!(imported.moves[5].target(), Square::A6);
+
+        let error = parse("1. e4 e5 2. Ke3").unwrap_err();
+        assert_eq!(error, "illegal move Ke3 after 2 moves");
+    }
+
+    #[test]
+    fn round_trip() {
+        let fen = "8/4P3/8/8/8/8/k7/4K3 b - - 0 7";
+        let record = Record {
+            white: "Human".to_string(),
+            black: "Human".to_string(),
+            fen: fen.to_string(),
+            started: 0,
+            moves: vec![("Kb2".to_string(), 1), ("e8=Q".to_string(), 2)],
+            result: "*",
+        };
+        let pgn = record.to_pgn();
+        assert!(pgn.contains("[FEN \"8/4P3/8/8/8/8/k7/4K3 b - - 0 7\"]"));
+        assert!(pgn.contains("7... Kb2 {[%emt 0:00:01]} 8. e8=Q"));
+        let imported = parse(&pgn).unwrap();
+        assert_eq!(imported.fen, fen);
+        assert_eq!(imported.moves.len(), 2);
+        assert!(imported.moves[1].is_promotion());
+    }
+}
@@ -0,0 +1,150 @@
+//! Path planning for carried pieces. All coordinates are measured in squares: `(0.0, 0.0)` is the
+//! outer corner of a1 next to the first file and rank and the centre of a square with file `f`
+//! and rank `r` is at `(f + 0.5, r + 0.5)`. The integer points are the corners between the
+//! squares, so a carried piece can travel along the lines between them without getting closer
+//! than half a square to any other piece.
+
+/// A point on the board measured in squares.
+pub type Point = (f32, f32);
+
+/// The carried piece may not get closer than this to the centre of any other piece.
+const MIN_CLEARANCE: f32 = 0.5;
+
+/// Extra cost for every waypoint, so that paths with fewer turns are preferred.
+const TURN_PENALTY: f32 = 0.5;
+
+/// Number of corners per line, including the ones on the edge of the board.
+const CORNERS: usize = 9;
+
+fn distance(a: Point, b: Point) -> f32 {
+    ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt()
+}
+
+/// Distance between the point `p` and the line segment from `a` to `b`.
+fn distance_to_segment(p: Point, a: Point, b: Point) -> f32 {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let len = dx * dx + dy * dy;
+    if len == 0.0 {
+        return distance(p, a);
+    }
+    let t = (((p.0 - a.0) * dx + (p.1 - a.1) * dy) / len).clamp(0.0, 1.0);
+    distance(p, (a.0 + t * dx, a.1 + t * dy))
+}
+
+fn is_clear(a: Point, b: Point, obstacles: &[Point]) -> bool {
+    obstacles
+        .iter()
+        .all(|&p| distance_to_segment(p, a, b) >= MIN_CLEARANCE)
+}
+
+/// Returns the waypoints for carrying a piece from `from` to `to` without knocking over any of
+/// the pieces in `obstacles`. The start point is not included, the last waypoint is always `to`.
+///
+/// If the straight line is clear it is used directly. Otherwise the piece leaves `from` to a
+/// corner between the squares, follows the lines between the squares and enters `to` from a
+/// corner again. If there is no such path the straight line is returned as well.
+pub fn plan_path(from: Point, to: Point, obstacles: &[Point]) -> Vec<Point> {
+    if is_clear(from, to, obstacles) {
+        return vec![to];
+    }
+
+    // Nodes 0..CORNERS^2 are the corners, followed by the start and the end point.
+    let mut nodes: Vec<Point> = (0..CORNERS * CORNERS)
+        .map(|i| ((i % CORNERS) as f32, (i / CORNERS) as f32))
+        .collect();
+    let start = nodes.len();
+    let end = start + 1;
+    nodes.push(from);
+    nodes.push(to);
+
+    let neighbours = |i: usize| -> Vec<usize> {
+        if i == start {
+            // leave the start point towards one of the surrounding corners
+            return (0..start)
+                .filter(|&j| distance(from, nodes[j]) < 1.0 && is_clear(from, nodes[j], obstacles))
+                .collect();
+        }
+        let mut res = Vec::new();
+        if distance(nodes[i], to) < 1.0 && is_clear(nodes[i], to, obstacles) {
+            res.push(end);
+        }
+        // follow the lines between the squares in all four directions
+        let (x, y) = ((i % CORNERS) as i32, (i / CORNERS) as i32);
+        for &(dx, dy) in &[(1, 0), (-1, 0), (0, 1), (0, -1)] {
+            let mut last = i;
+            let (mut nx, mut ny) = (x + dx, y + dy);
+            while (0..CORNERS as i32).contains(&nx) && (0..CORNERS as i32).contains(&ny) {
+                let j = ny as usize * CORNERS + nx as usize;
+                if !is_clear(nodes[last], nodes[j], obstacles) {
+                    break;
+                }
+                res.push(j);
+                last = j;
+                nx += dx;
+                ny += dy;
+            }
+        }
+        res
+    };
+
+    // Dijkstra, the graph is small enough that a linear search for the next node is fine.
+    let mut cost = vec![f32::INFINITY; nodes.len()];
+    let mut previous = vec![None; nodes.len()];
+    let mut done = vec![false; nodes.len()];
+    cost[start] = 0.0;
+    while let Some(i) = (0..nodes.len())
+        .filter(|&i| !done[i] && cost[i].is_finite())
+        .min_by(|&a, &b| cost[a].partial_cmp(&cost[b]).unwrap())
+    {
+        if i == end {
+            break;
+        }
+        done[i] = true;
+        for j in neighbours(i) {
+            let c = cost[i] + distance(nodes[i], nodes[j]) + TURN_PENALTY;
+            if c < cost[j] {
+                cost[j] = c;
+                previous[j] = Some(i);
+            }
+        }
+    }
+
+    if previous[end].is_none() {
+        return vec![to];
+    }
+    let mut path = Vec::new();
+    let mut i = end;
+    while i != start {
+        path.push(nodes[i]);
+        i = previous[i].unwrap();
+    }
+    path.reverse();
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn straight_line_if_clear() {
+        // a1 to h8 between the pieces on b1 and a2
+        let path = plan_path((0.5, 0.5), (7.5, 7.5), &[(1.5, 0.5), (0.5, 1.5)]);
+        assert_eq!(path, vec![(7.5, 7.5)]);
+    }
+
+    #[test]
+    fn detour_around_blocking_piece() {
+        // a1 to a3 with a piece on a2
+        let obstacles = [(0.5, 1.5)];
+        let path = plan_path((0.5, 0.5), (0.5, 2.5), &obstacles);
+        assert_eq!(path.len(), 3);
+        assert_eq!(path[2], (0.5, 2.5));
+
+        let mut last = (0.5, 0.5);
+        for &p in &path {
+            assert!(is_clear(last, p, &obstacles));
+            last = p;
+        }
+    }
+}
@@ -0,0 +1,536 @@
+//! The websocket protocols. Clients choose the protocol with the websocket subprotocol:
+//!
+//! - [`TEXT_SUB_PROTOCOL`] is the original ad-hoc text protocol. Moves are sent in coordinate
+//!   notation and everything else as `!command arguments`. It is still supported for old clients.
+//! - [`JSON_SUB_PROTOCOL`] exchanges json objects. Every [`Request`] carries an id and a `type`
+//!   and is answered with exactly one [`Message::Ok`] or [`Message::Error`] with the same id. The
+//!   first request has to be a [`Command::Hello`] with the [`VERSION`] of the protocol.
+//!
+//! Several clients can be connected at the same time, but only the one in control may change the
+//! game or move the robot. Control is given to the first client and handed on to the longest
+//! connected client when it disconnects. Whenever the game changes, the new state is sent to all
+//! other clients: [`Message::State`] in the json protocol and `!set <fen>` followed by the side to
+//! move or the result in the text protocol.
+//!
+//! The server plays the moves of the engine itself (cf. [`crate::engine`]), clients only send the
+//! moves of the human players. The moves of the engine reach all clients as a change of the game.
+//!
+//! A request that fails is answered with the reason, e.g. when the carriage would leave its bounds,
+//! and the server goes on serving. In the json protocol the error also names its `kind`, cf.
+//! [`crate::error::RoboChessError::kind`]. If the robot fails to carry out a move of the engine, a
+//! step of an arrangement or a move of a replay, all clients get a [`Message::Error`] without an id
+//! or `!error <reason>` in the text protocol.
+//!
+//! Promotions have to name the piece, e.g. `e7e8n`. If the robot can't do something on its own,
+//! like putting a promoted piece that isn't in the tray on the board, all clients get a
+//! [`Message::Notice`] or `!notice <text>` in the text protocol. If a human has to put the promoted
+//! piece on the board (cf. [`crate::config::PromotionPolicy`]), the game is paused until it is
+//! confirmed with [`Command::Placed`] or `!placed`.
+//!
+//! [`Command::Reset`] (`!reset [fen]`) and [`Command::Fen`] set up the start position or any other
+//! position with the robot, cf. [`crate::arrange`]. The pieces are carried one after another and
+//! all clients get a [`Message::Progress`] (`!progress <done> <total>`) after each of them. Until
+//! the last piece is in place, no other command that needs control is accepted.
+//!
+//! [`Command::Undo`] (`!undo [moves]`) carries the pieces back the same way. If the engine is to
+//! move afterwards it plays again, so a human playing against it takes back two moves.
+//!
+//! [`Command::Pgn`] (`!pgn`) returns the current game as a PGN (`!pgn <pgn>`), cf. [`crate::pgn`].
+//! [`Command::Import`] (`!import <pgn>`) sets up the start position of a PGN and replays its moves
+//! one after another with a pause in between, cf. [`crate::config::Replay`]. The engine doesn't
+//! play in a replayed game. The replay can be paused and resumed (`!replay pause`,
+//! `!replay resume`) and played move by move while it is paused (`!replay step`). All clients get a
+//! [`Message::Replay`] (`!replay <played> <total> [paused]`) whenever it advances or is paused.
+//!
+//! The game is saved after every move, cf. [`crate::journal`]. If the controller was restarted in
+//! the middle of a game, the [`Reply::Hello`] names the [`Saved`] game and text clients get
+//! `!saved <moves> <fen>` after the position. It is continued with [`Command::Resume`]
+//! (`!resume`) or dropped with [`Command::Discard`] (`!discard`) or by setting up a new position.
+//! Until then no moves are accepted. If the robot was interrupted, text clients also get a
+//! `!notice` saying where it stopped. A move it was interrupted in is rolled forward or back when
+//! the game is resumed (`!resume forward`, `!resume back`), cf. [`crate::journal`].
+//!
+//! If the gantry fails in the middle of a move, the move is rolled forward or back the same way
+//! without a restart. Until then the [`Game`] names the move as `moving`, text clients get a
+//! `!notice` when they connect and no moves are accepted and no pieces are arranged.
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Engine, Side};
+use crate::journal::Roll;
+
+pub const TEXT_SUB_PROTOCOL: &str = "robochess-websocket";
+pub const JSON_SUB_PROTOCOL: &str = "robochess-json";
+
+/// The version of the json protocol. It has to be increased for every incompatible change, i.e. one
+/// that breaks clients of the previous version like a renamed field. New commands, messages and
+/// fields don't need a new version. The web interface takes it from `webinterface/src/protocol.js`,
+/// which has to be changed along with it.
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Command {
+    /// Has to be the first request, fails if the client expects a different version.
+    Hello {
+        version: u32,
+    },
+    /// Plays a move in coordinate notation, e.g. `e2e4`.
+    Move {
+        #[serde(rename = "move")]
+        m: String,
+    },
+    /// Sets up the given position. The pieces are arranged by the robot unless `arrange` is
+    /// false, e.g. because they have been set up by hand.
+    Fen {
+        fen: String,
+        #[serde(default = "default_arrange")]
+        arrange: bool,
+    },
+    /// Returns the current position.
+    State,
+    /// Confirms that the promoted piece has been put on the board by hand.
+    Placed,
+    /// Arranges the pieces for the given position, or the start position without a FEN.
+    Reset {
+        fen: Option<String>,
+    },
+    /// Takes back the given number of moves, carrying the pieces back.
+    Undo {
+        #[serde(default = "default_undo")]
+        moves: usize,
+    },
+    /// Returns the current game as a PGN.
+    Pgn,
+    /// Replays the first game of the PGN on the board. `pause` overrides the seconds between two
+    /// moves of the config.
+    Import {
+        pgn: String,
+        pause: Option<f32>,
+    },
+    /// Stops the replay after the current move.
+    ReplayPause,
+    ReplayResume,
+    /// Plays the next move of a paused replay.
+    ReplayStep,
+    /// Continues the game that was saved before the controller was restarted or the move the
+    /// gantry failed in. If the robot was interrupted in a move, `roll` says whether the move is
+    /// finished or taken back.
+    Resume {
+        roll: Option<Roll>,
+    },
+    /// Drops the saved game, e.g. because the pieces have been cleared away by hand.
+    Discard,
+    /// Changes the settings of the engine for the current game, cf. [`Engine`].
+    Engine {
+        side: Option<Side>,
+        depth: u32,
+        time: Option<f32>,
+    },
+    /// Performs the init sequence of the gantry.
+    Home,
+    CalibrationStart,
+    CalibrationJog {
+        dx: i32,
+        dy: i32,
+    },
+    CalibrationConfirm,
+    CalibrationCancel,
+    Jog {
+        dx: i32,
+        dy: i32,
+    },
+    MoveTo {
+        x: i32,
+        y: i32,
+    },
+    MoveToSquare {
+        square: String,
+    },
+    Up,
+    Down,
+    Position,
+}
+
+fn default_arrange() -> bool {
+    true
+}
+
+fn default_undo() -> usize {
+    1
+}
+
+impl Command {
+    /// Returns whether only the client in control may send this command.
+    pub fn needs_control(&self) -> bool {
+        !matches!(self, Command::Hello { .. } | Command::State | Command::Pgn)
+    }
+
+    /// Parses a message of the text protocol. Returns `None` for unknown commands.
+    pub fn from_text(s: &str) -> Option<Self> {
+        if !s.starts_with('!') {
+            return Some(Command::Move { m: s.to_string() });
+        }
+        if let Some(fen) = s.strip_prefix("!fen ") {
+            return Some(Command::Fen {
+                fen: fen.to_string(),
+                arrange: true,
+            });
+        }
+        if let Some(pgn) = s.strip_prefix("!import ") {
+            return Some(Command::Import {
+                pgn: pgn.to_string(),
+                pause: None,
+            });
+        }
+        if let Some(fen) = s.strip_prefix("!reset ") {
+            return Some(Command::Reset {
+                fen: Some(fen.to_string()),
+            });
+        }
+        let args: Vec<&str> = s.split_whitespace().collect();
+        let parse = |s: &str| s.parse().ok();
+        let command = match args.as_slice() {
+            ["!calibrate"] => Command::Home,
+            ["!placed"] => Command::Placed,
+            ["!pgn"] => Command::Pgn,
+            ["!replay", "pause"] => Command::ReplayPause,
+            ["!replay", "resume"] => Command::ReplayResume,
+            ["!replay", "step"] => Command::ReplayStep,
+            ["!resume"] => Command::Resume { roll: None },
+            ["!resume", "forward"] => Command::Resume {
+                roll: Some(Roll::Forward),
+            },
+            ["!resume", "back"] => Command::Resume {
+                roll: Some(Roll::Back),
+            },
+            ["!discard"] => Command::Discard,
+            ["!reset"] => Command::Reset { fen: None },
+            ["!undo"] => Command::Undo { moves: 1 },
+            ["!undo", moves] => Command::Undo {
+                moves: moves.parse().ok()?,
+            },
+            ["!calibration", "start"] => Command::CalibrationStart,
+            ["!calibration", "jog", dx, dy] => Command::CalibrationJog {
+                dx: parse(dx)?,
+                dy: parse(dy)?,
+            },
+            ["!calibration", "confirm"] => Command::CalibrationConfirm,
+            ["!calibration", "cancel"] => Command::CalibrationCancel,
+            ["!jog", dx, dy] => Command::Jog {
+                dx: parse(dx)?,
+                dy: parse(dy)?,
+            },
+            ["!move", x, y] => Command::MoveTo {
+                x: parse(x)?,
+                y: parse(y)?,
+            },
+            ["!move", square] => Command::MoveToSquare {
+                square: square.to_string(),
+            },
+            ["!up"] => Command::Up,
+            ["!down"] => Command::Down,
+            ["!position"] => Command::Position,
+            ["!engine", "off"] => Command::Engine {
+                side: None,
+                depth: Engine::default().depth,
+                time: None,
+            },
+            ["!engine", side, depth] => Command::Engine {
+                side: match *side {
+                    "white" => Some(Side::White),
+                    "black" => Some(Side::Black),
+                    _ => return None,
+                },
+                depth: depth.parse().ok()?,
+                time: None,
+            },
+            _ => return None,
+        };
+        Some(command)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Request {
+    pub id: u64,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+/// Whose turn it is or how the game ended.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    White,
+    Black,
+    Checkmate,
+    Draw,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Game {
+    pub fen: String,
+    pub status: Status,
+    /// The square a human has to put the promoted piece on before the game goes on.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub awaited_piece: Option<String>,
+    /// The move the gantry failed in, which has to be rolled forward or back with
+    /// [`Command::Resume`] before the game goes on.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub moving: Option<String>,
+}
+
+/// A game that was saved before the controller was restarted and can be resumed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Saved {
+    /// The complete FEN of the position.
+    pub fen: String,
+    /// The number of moves that have been made.
+    pub moves: usize,
+    /// What the robot was doing when it was interrupted, e.g. `playing e2e4 with 0 of 1 pieces at
+    /// their destination`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interrupted: Option<String>,
+    /// The move the robot was interrupted in, which has to be rolled forward or back.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub moving: Option<String>,
+}
+
+/// The result of a successful command.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Reply {
+    Hello {
+        version: u32,
+        /// Whether this client is in control.
+        control: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        saved: Option<Box<Saved>>,
+        #[serde(flatten)]
+        game: Game,
+    },
+    Game(Game),
+    Engine(Engine),
+    Position {
+        x: i32,
+        y: i32,
+    },
+    /// The square the magnet has to be moved over next or `None` if the calibration is done.
+    Calibration {
+        next_square: Option<String>,
+    },
+    /// The number of pieces that have been carried so far and how many have to be carried in
+    /// total.
+    Progress {
+        done: usize,
+        total: usize,
+    },
+    Pgn {
+        pgn: String,
+    },
+    /// The number of moves of the replayed game that have been played, of how many.
+    Replay {
+        played: usize,
+        total: usize,
+        paused: bool,
+    },
+    None,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    Ok {
+        id: u64,
+        result: Reply,
+    },
+    /// The id is missing if the request could not be parsed or the error doesn't answer a request,
+    /// e.g. the robot failed to carry out a move of the engine. The latter is sent to all clients.
+    Error {
+        id: Option<u64>,
+        /// What went wrong, cf. [`crate::error::RoboChessError::kind`].
+        kind: &'static str,
+        reason: String,
+    },
+    /// Sent to all other clients after the game has changed.
+    State {
+        #[serde(flatten)]
+        game: Game,
+    },
+    /// Tells the players about something they have to do by hand.
+    Notice {
+        text: String,
+    },
+    /// Sent to all clients while the pieces are arranged, cf. [`Command::Reset`].
+    Progress {
+        done: usize,
+        total: usize,
+    },
+    /// Sent to all clients when the replay advances or is paused or resumed, cf.
+    /// [`Command::Import`].
+    Replay {
+        played: usize,
+        total: usize,
+        paused: bool,
+    },
+    /// Sent to a client when it gets control because the client in control disconnected.
+    Control,
+}
+
+impl Message {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("a message can always be serialized")
+    }
+}
+
+/// Parses a request of the json protocol. On failure the error message is returned.
+pub fn parse_request(s: &str) -> Result<Request, Message> {
+    let value: serde_json::Value = serde_json::from_str(s).map_err(|e| Message::Error {
+        id: None,
+        kind: "protocol",
+        reason: format!("invalid json: {}", e),
+    })?;
+    let id = value.get("id").and_then(|id| id.as_u64());
+    serde_json::from_value(value).map_err(|e| Message::Error {
+        id,
+        kind: "protocol",
+        reason: format!("invalid request: {}", e),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::RoboChessError;
+
+    #[test]
+    fn parse_json_request() {
+        let request = parse_request(r#"{"id": 3, "type": "move", "move": "e2e4"}"#).unwrap();
+        assert_eq!(request.id, 3);
+        assert_eq!(
+            request.command,
+            Command::Move {
+                m: "e2e4".to_string()
+            }
+        );
+
+        let request = parse_request(r#"{"id": 4, "type": "jog", "dx": -10, "dy": 5}"#).unwrap();
+        assert_eq!(request.command, Command::Jog { dx: -10, dy: 5 });
+
+        let request = parse_request(r#"{"id": 6, "type": "undo"}"#).unwrap();
+        assert_eq!(request.command, Command::Undo { moves: 1 });
+
+        let request =
+            parse_request(r#"{"id": 5, "type": "fen", "fen": "8/8/8/8/8/8/8/8 w - - 0 1"}"#)
+                .unwrap();
+        assert_eq!(
+            request.command,
+            Command::Fen {
+                fen: "8/8/8/8/8/8/8/8 w - - 0 1".to_string(),
+                arrange: true,
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_json_request() {
+        match parse_request(r#"{"id": 5, "type": "teleport"}"#) {
+            Err(Message::Error { id, .. }) => assert_eq!(id, Some(5)),
+            _ => panic!("request should be rejected"),
+        }
+        match parse_request("e2e4") {
+            Err(Message::Error { id, .. }) => assert_eq!(id, None),
+            _ => panic!("request should be rejected"),
+        }
+    }
+
+    #[test]
+    fn serialize_replies() {
+        let message = Message::Ok {
+            id: 1,
+            result: Reply::Position { x: 10, y: 20 },
+        };
+        assert_eq!(
+            message.to_json(),
+            r#"{"type":"ok","id":1,"result":{"x":10,"y":20}}"#
+        );
+        let message = Message::Ok {
+            id: 2,
+            result: Reply::None,
+        };
+        assert_eq!(message.to_json(), r#"{"type":"ok","id":2,"result":null}"#);
+        let e = RoboChessError::Busy("the pieces are being arranged".to_string());
+        let message = Message::Error {
+            id: Some(3),
+            kind: e.kind(),
+            reason: e.to_string(),
+        };
+        assert_eq!(
+            message.to_json(),
+            r#"{"type":"error","id":3,"kind":"busy","reason":"the pieces are being arranged"}"#
+        );
+    }
+
+    #[test]
+    fn web_interface_speaks_the_same_version() {
+        let expected = format!("export const VERSION = {};", VERSION);
+        assert!(include_str!("../../webinterface/src/protocol.js").contains(&expected));
+    }
+
+    #[test]
+    fn parse_text_commands() {
+        assert_eq!(
+            Command::from_text("!fen 8/8/8/8/8/8/8/8 w - - 0 1"),
+            Some(Command::Fen {
+                fen: "8/8/8/8/8/8/8/8 w - - 0 1".to_string(),
+                arrange: true,
+            })
+        );
+        assert_eq!(
+            Command::from_text("!calibration jog 1 -2"),
+            Some(Command::CalibrationJog { dx: 1, dy: -2 })
+        );
+        assert_eq!(
+            Command::from_text("!engine white 4"),
+            Some(Command::Engine {
+                side: Some(Side::White),
+                depth: 4,
+                time: None
+            })
+        );
+        assert_eq!(
+            Command::from_text("!reset"),
+            Some(Command::Reset { fen: None })
+        );
+        assert_eq!(
+            Command::from_text("!reset 8/8/8/8/8/8/8/8 w - - 0 1"),
+            Some(Command::Reset {
+                fen: Some("8/8/8/8/8/8/8/8 w - - 0 1".to_string())
+            })
+        );
+        assert_eq!(
+            Command::from_text("!undo 2"),
+            Some(Command::Undo { moves: 2 })
+        );
+        assert_eq!(
+            Command::from_text("!import 1. e4 e5\n2. Nf3 *"),
+            Some(Command::Import {
+                pgn: "1. e4 e5\n2. Nf3 *".to_string(),
+                pause: None,
+            })
+        );
+        assert_eq!(
+            Command::from_text("!replay step"),
+            Some(Command::ReplayStep)
+        );
+        assert_eq!(
+            Command::from_text("!resume back"),
+            Some(Command::Resume {
+                roll: Some(Roll::Back)
+            })
+        );
+        assert_eq!(Command::from_text("!jog a 1"), None);
+        assert_eq!(Command::from_text("!unknown"), None);
+    }
+}
@@ -1,4 +1,13 @@
+use crate::arrange::{self, Arrangement, Place, Step};
+use crate::config::{Config, Geometry, Promotion, PromotionPolicy};
+use crate::engine::coordinate_notation;
+use crate::error::RoboChessError;
+use crate::gantry::Gantry;
+use crate::graveyard::{self, Graveyard, SLOTS};
 use crate::hbot::HBot;
+use crate::journal::{Captured, Carry, Entry, Journal, Phase, Progress, Roll, Task};
+use crate::pgn::{self, Record};
+use crate::planner::{plan_path, Point};
 use chers::BitMove;
 use chers::Color;
 use chers::ParsedMove;
@@ -6,264 +15,1297 @@ use chers::Piece;
 use chers::PieceType;
 use chers::Position;
 use chers::Square;
+use notation::{parse_board, Board};
 
-const X_OFFSET: i32 = 100;
-const Y_OFFSET: i32 = 0;
-const Y_PLACEMENT_OFFSET: i32 = 50;
-const SIZE_OFF_SQUARE: i32 = 264;
-
-fn get_square_coordinates(sq: Square) -> (i32, i32) {
-    let rank = sq.rank().to_i32();
-    let file = sq.file().to_i32();
-    let x = X_OFFSET + (7 - file) * SIZE_OFF_SQUARE + SIZE_OFF_SQUARE / 2;
-    let y = Y_OFFSET + rank * SIZE_OFF_SQUARE + SIZE_OFF_SQUARE / 2;
-    (x, y)
+use std::fmt;
+use std::path::PathBuf;
+
+#[cfg(test)]
+mod tests;
+
+/// Something the players have to be told about a move, because the robot couldn't do everything on
+/// its own.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Notice {
+    /// There was no piece of the promoted type in the tray, so the promotion square is still empty
+    /// and the game waits for [`RoboChess::confirm_placement`].
+    MissingPromotionPiece {
+        square: Square,
+        white: bool,
+        piece: PieceType,
+    },
+    /// Another piece of the tray has been put on the promotion square in place of the promoted
+    /// piece.
+    Substitute {
+        square: Square,
+        white: bool,
+        piece: PieceType,
+        substitute: PieceType,
+    },
+    /// The tray is full, so the captured piece is still on the square.
+    TrayFull { square: Square, white: bool },
+    /// The controller was restarted while the robot was carrying out the task, so the pieces may
+    /// not stand where they should, cf. [`crate::journal`].
+    Interrupted { task: Task },
+}
+
+fn piece_name(piece: PieceType) -> &'static str {
+    match piece.to_string().to_ascii_lowercase().as_str() {
+        "q" => "queen",
+        "r" => "rook",
+        "b" => "bishop",
+        "n" => "knight",
+        "p" => "pawn",
+        _ => "piece",
+    }
+}
+
+impl fmt::Display for Notice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Notice::MissingPromotionPiece {
+                square,
+                white,
+                piece,
+            } => {
+                let color = if *white { "white" } else { "black" };
+                write!(
+                    f,
+                    "there is no {} {} in the tray, please put one on {} and confirm it",
+                    color,
+                    piece_name(*piece),
+                    square
+                )
+            }
+            Notice::Substitute {
+                square,
+                white,
+                piece,
+                substitute,
+            } => {
+                let color = if *white { "white" } else { "black" };
+                write!(
+                    f,
+                    "the {} {} on {} is a {} from the tray, please turn it upside down",
+                    color,
+                    piece_name(*piece),
+                    square,
+                    piece_name(*substitute)
+                )
+            }
+            Notice::TrayFull { square, white } => {
+                let color = if *white { "white" } else { "black" };
+                write!(
+                    f,
+                    "the {} tray is full, please take the captured piece off {}",
+                    color, square
+                )
+            }
+            Notice::Interrupted { task } => write!(
+                f,
+                "the robot was interrupted while {}, please put the pieces back where they stood \
+                 before",
+                task
+            ),
+        }
+    }
+}
+
+pub fn square_center(sq: Square) -> Point {
+    (
+        sq.file().to_i32() as f32 + 0.5,
+        sq.rank().to_i32() as f32 + 0.5,
+    )
+}
+
+/// Returns the centres of all pieces in `position` except the ones on the given squares.
+fn obstacles(position: &Position, except: &[Square]) -> Vec<Point> {
+    (0..64)
+        .map(square)
+        .filter(|sq| position.get_square(*sq) != Piece::EMPTY && !except.contains(sq))
+        .map(square_center)
+        .collect()
+}
+
+/// Where the piece for a promotion is taken from.
+enum PromotionSource {
+    /// The piece in the given slot of the tray.
+    Tray(usize),
+    /// The piece of the given type in the given slot of the tray, which stands in for the promoted
+    /// piece.
+    Substitute(usize, PieceType),
+    /// The spare queen of [`Promotion`].
+    Reserve((i32, i32)),
+}
+
+/// A move that has been made, with everything that is needed to record it and to take it back.
+#[derive(Clone)]
+pub struct Played {
+    pub m: BitMove,
+    /// The move in standard algebraic notation.
+    pub san: String,
+    /// When the move was finished, in seconds since the unix epoch.
+    pub time: u64,
+    /// The complete FEN of the position before the move.
+    pub fen: String,
+    /// The substitutes before the move.
+    pub substitutes: Vec<(Square, PieceType)>,
+    /// The slot and the physical type of the captured piece. `None` if nothing was captured or the
+    /// tray was full.
+    pub captured: Option<(usize, PieceType)>,
+    /// The slot of the promoted pawn.
+    pub pawn: Option<usize>,
+    /// Where the spare queen was taken from if the pawn was promoted to it, cf. [`Promotion`].
+    pub reserve: Option<(i32, i32)>,
+}
+
+fn index(sq: Square) -> usize {
+    (8 * sq.rank().to_i32() + sq.file().to_i32()) as usize
+}
+
+/// Returns the square with the given index, cf. [`index`].
+fn square(i: usize) -> Square {
+    const FIRST_RANK: [Square; 8] = [
+        Square::A1,
+        Square::B1,
+        Square::C1,
+        Square::D1,
+        Square::E1,
+        Square::F1,
+        Square::G1,
+        Square::H1,
+    ];
+    let sq = FIRST_RANK[i % 8];
+    Square::new(sq.file(), sq.rank() + (i / 8) as u8)
+}
+
+/// Parses a square like `e4`.
+pub fn parse_square(s: &str) -> Option<Square> {
+    let mut chars = s.chars();
+    let file = chars.next().filter(|c| ('a'..='h').contains(c))?;
+    let rank = chars.next().filter(|c| ('1'..='8').contains(c))?;
+    if chars.next().is_some() {
+        return None;
+    }
+    Some(square(
+        8 * (rank as u8 - b'1') as usize + (file as u8 - b'a') as usize,
+    ))
 }
 
-pub struct RoboChess {
-    pub controller: HBot,
+/// Converts substitutes to the way they are saved in the journal.
+fn save_substitutes(substitutes: &[(Square, PieceType)]) -> Vec<(String, String)> {
+    substitutes
+        .iter()
+        .map(|(sq, piece)| (sq.to_string(), piece.to_string().to_ascii_lowercase()))
+        .collect()
+}
+
+/// Parses substitutes saved in the journal.
+fn parse_substitutes(substitutes: &[(String, String)]) -> Result<Vec<(Square, PieceType)>, String> {
+    substitutes
+        .iter()
+        .map(|(sq, piece)| {
+            let square = parse_square(sq).ok_or_else(|| format!("invalid square {}", sq))?;
+            let piece = graveyard::parse_piece_type(piece)
+                .ok_or_else(|| format!("unknown piece {}", piece))?;
+            Ok((square, piece))
+        })
+        .collect()
+}
+
+/// Returns how a slot of a tray is called for the players.
+fn tray_label(white: bool, slot: usize) -> String {
+    let color = if white { "white" } else { "black" };
+    format!("slot {} of the {} tray", slot + 1, color)
+}
+
+/// A move whose carries have been planned, with the state of the game once they are done.
+struct PlannedMove {
+    played: Played,
+    carries: Vec<Carry>,
+    notices: Vec<Notice>,
+    graveyard: Graveyard,
+    substitutes: Vec<(Square, PieceType)>,
+    reserve_white_used: bool,
+    reserve_black_used: bool,
+    awaited_piece: Option<Square>,
+}
+
+/// The steps to take back moves, planned on a copy of the board and the trays.
+struct Undo {
+    board: Board,
+    graveyard: Graveyard,
+    steps: Vec<Step>,
+    reserve_white_used: bool,
+    reserve_black_used: bool,
+}
+
+impl Undo {
+    fn carry(&mut self, piece: char, from: Place, to: Place) {
+        let step = Step { piece, from, to };
+        step.apply(&mut self.board, &mut self.graveyard);
+        self.steps.push(step);
+    }
+
+    /// Returns a slot holding a piece of the given type, preferably `slot`, which may have been
+    /// emptied and reused by a later promotion.
+    fn find(&self, white: bool, slot: usize, piece: PieceType) -> Option<usize> {
+        if self.graveyard.slots(white)[slot] == Some(piece) {
+            Some(slot)
+        } else {
+            self.graveyard.find(white, piece)
+        }
+    }
+
+    fn take_back(&mut self, played: &Played) -> Result<(), String> {
+        let m = played.m;
+        let (origin, target) = (index(m.origin()), index(m.target()));
+        let piece = self.board[target]
+            .ok_or_else(|| format!("the piece moved to {} is missing", m.target()))?;
+        let white = piece.is_ascii_uppercase();
+        if m.is_promotion() {
+            let pawn = played
+                .pawn
+                .and_then(|slot| self.find(white, slot, PieceType::PAWN))
+                .ok_or_else(|| format!("the pawn promoted on {} is not in the tray", m.target()))?;
+            // the pawn leaves its slot first, so that the promoted piece can take it
+            let pawn_letter = arrange::piece_letter(white, PieceType::PAWN);
+            self.carry(pawn_letter, Place::Slot(white, pawn), Place::Square(origin));
+            if let Some(reserve) = played.reserve {
+                // the spare queen goes back to its place, so that it can be used again
+                self.carry(piece, Place::Square(target), Place::Reserve(white, reserve));
+                if white {
+                    self.reserve_white_used = false;
+                } else {
+                    self.reserve_black_used = false;
+                }
+            } else {
+                let slot = self.graveyard.first_free(white).ok_or_else(|| {
+                    format!(
+                        "there is no free slot for the piece promoted on {}",
+                        m.target()
+                    )
+                })?;
+                self.carry(piece, Place::Square(target), Place::Slot(white, slot));
+            }
+        } else {
+            self.carry(piece, Place::Square(target), Place::Square(origin));
+            if m.is_castle() {
+                let rank = origin - origin % 8;
+                let (from, to) = if m.is_king_side_castle() {
+                    (rank + 5, rank + 7)
+                } else {
+                    (rank + 3, rank)
+                };
+                let rook = self.board[from]
+                    .ok_or_else(|| format!("the rook of the castling {} is missing", m))?;
+                self.carry(rook, Place::Square(from), Place::Square(to));
+            }
+        }
+        if m.is_capture() {
+            // a pawn captured en passant stood beside the origin
+            let square = if m.is_en_passant() {
+                origin - origin % 8 + target % 8
+            } else {
+                target
+            };
+            let (slot, captured) = played
+                .captured
+                .and_then(|(slot, piece)| Some((self.find(!white, slot, piece)?, piece)))
+                .ok_or_else(|| {
+                    format!("the piece captured on {} is not in the tray", m.target())
+                })?;
+            let letter = arrange::piece_letter(!white, captured);
+            self.carry(letter, Place::Slot(!white, slot), Place::Square(square));
+        }
+        Ok(())
+    }
+}
+
+pub struct RoboChess<G: Gantry = HBot> {
+    pub controller: G,
+    pub geometry: Geometry,
+    pub promotion: Promotion,
     pub position: Position,
-    pub captured_pieces_white: Vec<Piece>,
-    pub captured_pieces_black: Vec<Piece>,
+    pub graveyard: Graveyard,
+    /// Where the graveyard is saved after every change, cf. [`Geometry::graveyard_file`].
+    pub graveyard_file: Option<PathBuf>,
+    /// Where the game is saved after every move, cf. [`Geometry::journal_file`].
+    pub journal_file: Option<PathBuf>,
+    /// Whether the spare queens of [`Promotion`] have been used.
+    pub reserve_white_used: bool,
+    pub reserve_black_used: bool,
+    /// The pieces that physically stand on the squares in place of a promoted piece.
+    pub substitutes: Vec<(Square, PieceType)>,
+    /// The square a human has to put a promoted piece on. No move may be made until it is
+    /// confirmed with [`RoboChess::confirm_placement`].
+    pub awaited_piece: Option<Square>,
+    /// The moves since the position was set up, which can be taken back with
+    /// [`RoboChess::plan_undo`].
+    pub history: Vec<Played>,
+    /// When the position was set up, in seconds since the unix epoch.
+    pub started: u64,
+    /// The move the gantry failed in. The pieces don't stand where [`RoboChess::position`] says
+    /// until it is rolled forward or back with [`RoboChess::recover`], so no other move may be
+    /// made and no pieces may be arranged.
+    pub interrupted: Option<Progress>,
 }
 
-impl RoboChess {
-    pub fn new() -> Self {
-        let controller = HBot::new(27, 17, 6, 26, 16, 5, 13);
+impl RoboChess<HBot> {
+    /// Creates a new RoboChess which drives the hardware. Fails if the carriage can't be homed.
+    pub fn new(config: &Config) -> Result<Self, RoboChessError> {
+        Ok(Self::with_config(HBot::new(config)?, config))
+    }
+}
+
+impl<G: Gantry> RoboChess<G> {
+    /// Creates a new RoboChess which uses the given gantry to move the pieces.
+    pub fn with_gantry(controller: G, geometry: Geometry) -> Self {
         let position = Position::new();
         Self {
             controller,
+            geometry,
+            promotion: Promotion::default(),
             position,
-            captured_pieces_white: Vec::new(),
-            captured_pieces_black: Vec::new(),
+            graveyard: Graveyard::new(),
+            graveyard_file: None,
+            journal_file: None,
+            reserve_white_used: false,
+            reserve_black_used: false,
+            substitutes: Vec::new(),
+            awaited_piece: None,
+            history: Vec::new(),
+            started: pgn::now(),
+            interrupted: None,
+        }
+    }
+
+    /// Creates a new RoboChess which uses the given gantry and the settings of the config. The
+    /// graveyard is saved to [`Geometry::graveyard_file`] and the game to
+    /// [`Geometry::journal_file`].
+    pub fn with_config(controller: G, config: &Config) -> Self {
+        let mut robochess = Self::with_gantry(controller, config.geometry.clone());
+        robochess.promotion = config.promotion.clone();
+        robochess.graveyard_file = Some(config.geometry.graveyard_file.clone());
+        robochess.journal_file = Some(config.geometry.journal_file.clone());
+        robochess
+    }
+
+    /// Saves the graveyard, so that a restart doesn't forget where the captured pieces are.
+    fn save_graveyard(&self) {
+        if let Some(path) = &self.graveyard_file {
+            if let Err(e) = self.graveyard.save(path) {
+                eprintln!("could not save {}: {}", path.display(), e);
+            }
         }
     }
 
-    /// Returns false if the move is illegal
-    pub fn make_move(&mut self, parsed_move: ParsedMove) -> bool {
-        // get the BitMove corresponding to the move, because the bit_move carries more information
-        let mut m = BitMove::NULL;
-        let moves = self.position.generate_legal_moves();
-        for bit_move in moves {
-            if bit_move == parsed_move {
-                m = bit_move;
-                break;
+    /// Returns the journal of the game. `task` is what the robot is about to do, by default the
+    /// move it was interrupted in.
+    pub fn journal(&self, task: Option<Task>) -> Journal {
+        let task = task.or_else(|| self.interrupted.clone().map(Task::Move));
+        let (moving, arranging) = match task {
+            Some(Task::Move(progress)) => (Some(progress), None),
+            Some(Task::Arrangement(fen)) => (None, Some(fen)),
+            None => (None, None),
+        };
+        let (done, phase, back) = moving
+            .as_ref()
+            .map_or((0, None, false), |p| (p.done, p.phase, p.back));
+        let (moving, carries) = moving.map_or((None, Vec::new()), |p| (Some(p.m), p.carries));
+        Journal {
+            fen: self.position.to_fen(),
+            started: self.started,
+            reserve_white_used: self.reserve_white_used,
+            reserve_black_used: self.reserve_black_used,
+            awaited_piece: self.awaited_piece.map(|sq| sq.to_string()),
+            carriage: self.controller.position(),
+            moving,
+            done,
+            phase,
+            back,
+            arranging,
+            substitutes: save_substitutes(&self.substitutes),
+            carries,
+            history: self
+                .history
+                .iter()
+                .map(|played| Entry {
+                    m: coordinate_notation(played.m),
+                    san: played.san.clone(),
+                    time: played.time,
+                    fen: played.fen.clone(),
+                    pawn: played.pawn,
+                    reserve: played.reserve,
+                    substitutes: save_substitutes(&played.substitutes),
+                    captured: played.captured.map(|(slot, piece)| Captured {
+                        slot,
+                        piece: piece.to_string().to_ascii_lowercase(),
+                    }),
+                })
+                .collect(),
+            graveyard: self.graveyard.to_trays(),
+        }
+    }
+
+    /// Saves the journal, so that the game can be resumed after a restart.
+    pub fn save_journal(&self, task: Option<Task>) {
+        if let Some(path) = &self.journal_file {
+            if let Err(e) = self.journal(task).save(path) {
+                eprintln!("could not save {}: {}", path.display(), e);
             }
         }
-        if m == BitMove::NULL {
-            // illegal move
+    }
+
+    /// Continues the game of the journal, e.g. after a restart. The pieces are assumed to stand
+    /// where the journal says, except for an interrupted move, which becomes
+    /// [`RoboChess::interrupted`] until it is finished with [`RoboChess::recover`]. Returns the
+    /// reason if the journal is invalid, nothing is changed then.
+    pub fn restore(&mut self, journal: &Journal) -> Result<(), String> {
+        let position =
+            Position::from_fen(&journal.fen).map_err(|_| format!("invalid fen {}", journal.fen))?;
+        let graveyard = Graveyard::from_trays(&journal.graveyard).map_err(|e| e.to_string())?;
+        let awaited_piece = match &journal.awaited_piece {
+            Some(sq) => Some(parse_square(sq).ok_or_else(|| format!("invalid square {}", sq))?),
+            None => None,
+        };
+        let substitutes = parse_substitutes(&journal.substitutes)?;
+        let task = journal.task();
+        if let Some(Task::Move(progress)) = &task {
+            if !position
+                .generate_legal_moves()
+                .into_iter()
+                .any(|m| coordinate_notation(m) == progress.m)
+            {
+                return Err(format!("illegal move {} in {}", progress.m, journal.fen));
+            }
+            let consistent = match (progress.phase, progress.back) {
+                (_, _) if progress.done > progress.carries.len() => false,
+                (Some(_), true) => progress.done > 0,
+                (Some(_), false) => progress.done < progress.carries.len(),
+                (None, _) => true,
+            };
+            if !consistent {
+                return Err(format!("invalid progress of the move {}", progress.m));
+            }
+        }
+        let mut history = Vec::new();
+        for entry in &journal.history {
+            let before =
+                Position::from_fen(&entry.fen).map_err(|_| format!("invalid fen {}", entry.fen))?;
+            let m = before
+                .generate_legal_moves()
+                .into_iter()
+                .find(|&m| coordinate_notation(m) == entry.m)
+                .ok_or_else(|| format!("illegal move {} in {}", entry.m, entry.fen))?;
+            let captured = match &entry.captured {
+                Some(Captured { slot, piece }) if *slot < SLOTS => {
+                    let piece = graveyard::parse_piece_type(piece)
+                        .ok_or_else(|| format!("unknown piece {}", piece))?;
+                    Some((*slot, piece))
+                }
+                Some(Captured { slot, .. }) => return Err(format!("invalid slot {}", slot)),
+                None => None,
+            };
+            history.push(Played {
+                m,
+                san: entry.san.clone(),
+                time: entry.time,
+                fen: entry.fen.clone(),
+                substitutes: parse_substitutes(&entry.substitutes)?,
+                captured,
+                pawn: entry.pawn,
+                reserve: entry.reserve,
+            });
+        }
+
+        self.position = position;
+        self.graveyard = graveyard;
+        self.save_graveyard();
+        self.reserve_white_used = journal.reserve_white_used;
+        self.reserve_black_used = journal.reserve_black_used;
+        self.substitutes = substitutes;
+        self.awaited_piece = awaited_piece;
+        self.history = history;
+        self.started = journal.started;
+        self.interrupted = match task {
+            Some(Task::Move(progress)) => Some(progress),
+            _ => None,
+        };
+        self.save_journal(None);
+        Ok(())
+    }
+
+    /// Sets up a new position without moving any pieces. The trays are assumed to be empty and
+    /// the spare queens to be in place.
+    pub fn set_position(&mut self, position: Position) {
+        self.position = position;
+        self.graveyard.clear();
+        self.save_graveyard();
+        self.reserve_white_used = false;
+        self.reserve_black_used = false;
+        self.substitutes.clear();
+        self.awaited_piece = None;
+        self.history.clear();
+        self.started = pgn::now();
+        self.save_journal(None);
+    }
+
+    /// Confirms that a human has put the promoted piece on the board. Returns false if no piece
+    /// was awaited.
+    pub fn confirm_placement(&mut self) -> bool {
+        if self.awaited_piece.take().is_none() {
             return false;
         }
+        self.save_journal(None);
+        true
+    }
+
+    /// Decides where the piece for a promotion to `piece` is taken from, cf. [`PromotionPolicy`].
+    /// Returns `None` if a human has to bring it.
+    fn promotion_source(
+        &self,
+        graveyard: &Graveyard,
+        white: bool,
+        piece: PieceType,
+    ) -> Option<PromotionSource> {
+        let (reserve, reserve_used) = if white {
+            (self.promotion.reserve_white, self.reserve_white_used)
+        } else {
+            (self.promotion.reserve_black, self.reserve_black_used)
+        };
+        if let Some(slot) = graveyard.find(white, piece) {
+            return Some(PromotionSource::Tray(slot));
+        }
+        if let (PieceType::QUEEN, Some(xy), false) = (piece, reserve, reserve_used) {
+            return Some(PromotionSource::Reserve(xy));
+        }
+        if self.promotion.policy == PromotionPolicy::Substitute {
+            return graveyard
+                .find(white, PieceType::ROOK)
+                .or_else(|| graveyard.find_any(white))
+                .and_then(|slot| {
+                    let substitute = graveyard.slots(white)[slot]?;
+                    Some(PromotionSource::Substitute(slot, substitute))
+                });
+        }
+        None
+    }
+
+    /// Returns the legal move matching the parsed move. A promotion only matches if the parsed move
+    /// has the same promotion piece.
+    pub fn find_move(&self, parsed_move: ParsedMove) -> Option<BitMove> {
+        // the BitMove carries more information than the ParsedMove
+        self.position
+            .generate_legal_moves()
+            .into_iter()
+            .find(|&bit_move| bit_move == parsed_move)
+    }
 
+    /// Makes the move given in coordinate notation on the board and returns what the players have
+    /// to be told about it.
+    pub fn make_move(&mut self, m: &str) -> Result<Vec<Notice>, RoboChessError> {
+        let bit_move = ParsedMove::from_coordinate_notation(m)
+            .ok()
+            .and_then(|parsed_move| self.find_move(parsed_move))
+            .ok_or_else(|| RoboChessError::IllegalMove(m.to_string()))?;
+        self.play(bit_move)
+    }
+
+    /// Makes a legal move on the board and returns what the players have to be told about it. If
+    /// the gantry fails the position is left as it was before the move, but some pieces may
+    /// already have been carried, so the move becomes [`RoboChess::interrupted`].
+    pub fn play(&mut self, m: BitMove) -> Result<Vec<Notice>, RoboChessError> {
+        self.check_interrupted()?;
+        let planned = self.plan_move(m);
+        let mut progress = Progress {
+            m: coordinate_notation(m),
+            carries: planned.carries.clone(),
+            done: 0,
+            phase: None,
+            back: false,
+        };
+        self.save_journal(Some(Task::Move(progress.clone())));
+        if let Err(e) = self.carry_pieces(&mut progress) {
+            self.interrupted = Some(progress);
+            return Err(e);
+        }
+        Ok(self.finish_move(planned))
+    }
+
+    /// Fails if the robot was interrupted in a move, cf. [`RoboChess::interrupted`].
+    pub fn check_interrupted(&self) -> Result<(), RoboChessError> {
+        match &self.interrupted {
+            Some(progress) => Err(RoboChessError::Busy(format!(
+                "the robot was interrupted while {}, roll the move forward or back first",
+                progress
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Plans which pieces have to be carried for a legal move and how, without moving anything.
+    fn plan_move(&self, m: BitMove) -> PlannedMove {
+        let g = &self.geometry;
+        let white = self.position.side_to_move() == Color::WHITE;
+        // only pieces of the side to move are carried, except for captured ones
+        let letter = |sq: Square| arrange::piece_letter(white, self.physical_piece(sq));
+        let mut planned = PlannedMove {
+            played: Played {
+                m,
+                san: notation::san(&self.position, m),
+                time: 0,
+                fen: self.position.to_fen(),
+                substitutes: self.substitutes.clone(),
+                captured: None,
+                pawn: None,
+                reserve: None,
+            },
+            carries: Vec::new(),
+            notices: Vec::new(),
+            graveyard: self.graveyard.clone(),
+            substitutes: self.substitutes.clone(),
+            reserve_white_used: self.reserve_white_used,
+            reserve_black_used: self.reserve_black_used,
+            awaited_piece: self.awaited_piece,
+        };
+
+        let capture_square = if m.is_en_passant() {
+            if white {
+                Square::new(m.target().file(), m.target().rank() - 1)
+            } else {
+                Square::new(m.target().file(), m.target().rank() + 1)
+            }
+        } else {
+            m.target()
+        };
         if m.is_capture() {
-            let capture_square = if m.is_en_passant() {
-                if self.position.side_to_move() == Color::WHITE {
-                    Square::new(m.target().file(), m.target().rank() - 1)
-                } else {
-                    Square::new(m.target().file(), m.target().rank() + 1)
+            let piece = self.physical_piece(capture_square);
+            match self.plan_capture(
+                &mut planned.graveyard,
+                &mut planned.substitutes,
+                capture_square,
+                !white,
+                &obstacles(&self.position, &[capture_square]),
+            ) {
+                Ok((slot, carry)) => {
+                    planned.played.captured = Some((slot, piece));
+                    planned.carries.push(carry);
                 }
-            } else {
-                m.target()
-            };
-            self.capture_piece(capture_square, self.position.side_to_move());
+                Err(notice) => planned.notices.push(notice),
+            }
         }
 
         if m.is_promotion() {
-            if self.position.side_to_move() == Color::WHITE {
-                if let Some(pos) = self
-                    .captured_pieces_white
-                    .iter()
-                    .position(|&x| x.piece_type() == m.promotion_piece())
-                {
-                    let x1 = X_OFFSET - SIZE_OFF_SQUARE / 4;
-                    let y1 = pos as i32 * SIZE_OFF_SQUARE / 2 + SIZE_OFF_SQUARE / 4;
-                    self.controller.move_to_xy_fast(x1, y1);
-                    self.controller.up();
-                    self.controller.move_to_xy_slow(0, y1);
-                    self.controller.move_to_xy_slow(0, 2200);
-                    let (x2, y2) = get_square_coordinates(m.target());
-                    self.controller.move_to_xy_slow(x2, 2200);
-                    self.controller.move_to_xy_slow(x2, y2 + Y_PLACEMENT_OFFSET);
-                    self.controller.down();
-                    self.captured_pieces_white[pos] = Piece::EMPTY;
+            let piece = m.promotion_piece();
+            match self.promotion_source(&planned.graveyard, white, piece) {
+                Some(source) => {
+                    let (start, path, from) = match source {
+                        PromotionSource::Tray(slot) | PromotionSource::Substitute(slot, _) => {
+                            // the pawn still stands on its origin
+                            let (start, path) = self.route(
+                                Place::Slot(white, slot),
+                                Place::Square(index(m.target())),
+                                &obstacles(&self.position, &[m.target()]),
+                            );
+                            (start, path, tray_label(white, slot))
+                        }
+                        PromotionSource::Reserve(xy) => {
+                            let (start, path) = self.route(
+                                Place::Reserve(white, xy),
+                                Place::Square(index(m.target())),
+                                &[],
+                            );
+                            (start, path, "the reserve".to_string())
+                        }
+                    };
+                    let mut carried = piece;
+
+                    match source {
+                        PromotionSource::Tray(slot) => {
+                            planned.graveyard.remove(white, slot);
+                        }
+                        PromotionSource::Substitute(slot, substitute) => {
+                            planned.graveyard.remove(white, slot);
+                            planned.substitutes.push((m.target(), substitute));
+                            planned.notices.push(Notice::Substitute {
+                                square: m.target(),
+                                white,
+                                piece,
+                                substitute,
+                            });
+                            carried = substitute;
+                        }
+                        PromotionSource::Reserve(xy) => {
+                            planned.played.reserve = Some(xy);
+                            if white {
+                                planned.reserve_white_used = true;
+                            } else {
+                                planned.reserve_black_used = true;
+                            }
+                        }
+                    }
+                    planned.carries.push(Carry {
+                        piece: arrange::piece_letter(white, carried),
+                        from,
+                        to: m.target().to_string(),
+                        start,
+                        path,
+                        settle: false,
+                    });
                 }
-            } else {
-                if let Some(pos) = self
-                    .captured_pieces_black
-                    .iter()
-                    .position(|&x| x.piece_type() == m.promotion_piece())
-                {
-                    let x1 = X_OFFSET + 33 * SIZE_OFF_SQUARE / 4;
-                    let y1 = 8 * SIZE_OFF_SQUARE - pos as i32 * SIZE_OFF_SQUARE / 2
-                        + SIZE_OFF_SQUARE / 4;
-                    self.controller.move_to_xy_fast(x1, y1);
-                    self.controller.up();
-                    self.controller.move_to_xy_slow(x1, y1);
-                    self.controller.move_to_xy_slow(x1, 0);
-                    let (x2, y2) = get_square_coordinates(m.target());
-                    self.controller.move_to_xy_slow(x2, 0);
-                    self.controller.move_to_xy_slow(x2, y2 + Y_PLACEMENT_OFFSET);
-                    self.controller.down();
-                    self.captured_pieces_black[pos] = Piece::EMPTY;
+                None => {
+                    planned.awaited_piece = Some(m.target());
+                    planned.notices.push(Notice::MissingPromotionPiece {
+                        square: m.target(),
+                        white,
+                        piece,
+                    });
                 }
             }
-            self.capture_piece(m.origin(), !self.position.side_to_move());
-        } else if m.is_castle() {
-            if self.position.side_to_move() == Color::WHITE {
-                if m.is_king_side_castle() {
-                    self.move_to_square_fast(Square::E1);
-                    self.controller.up();
-                    self.controller.wait();
-                    let (x1, _) = get_square_coordinates(Square::E1);
-                    let (x2, y2) = get_square_coordinates(Square::G1);
-                    self.controller.move_to_xy_slow(x1, 0);
-                    self.controller.move_to_xy_slow(x2, 0);
-                    self.controller.down();
-                    self.move_to_square_fast(Square::H1);
-                    self.controller.up();
-                    self.move_to_square_slow(Square::F1);
-                    self.controller.down();
-                    self.controller.move_to_xy_fast(x2, 0);
-                    self.controller.up();
-                    self.controller.move_to_xy_slow(x2, y2 + Y_PLACEMENT_OFFSET);
-                    self.controller.down();
-                } else {
-                    self.move_to_square_fast(Square::E1);
-                    self.controller.up();
-                    self.controller.wait();
-                    let (x1, _) = get_square_coordinates(Square::E1);
-                    let (x2, y2) = get_square_coordinates(Square::C1);
-                    self.controller.move_to_xy_slow(x1, 0);
-                    self.controller.move_to_xy_slow(x2, 0);
-                    self.controller.down();
-                    self.move_to_square_fast(Square::A1);
-                    self.controller.up();
-                    self.move_to_square_slow(Square::D1);
-                    self.controller.down();
-                    self.controller.move_to_xy_fast(x2, 0);
-                    self.controller.up();
-                    self.controller.move_to_xy_slow(x2, y2 + Y_PLACEMENT_OFFSET);
-                    self.controller.down();
-                }
-            } else {
-                if m.is_king_side_castle() {
-                    self.move_to_square_fast(Square::E8);
-                    self.controller.up();
-                    self.controller.wait();
-                    let (x1, _) = get_square_coordinates(Square::E8);
-                    let (x2, y2) = get_square_coordinates(Square::G8);
-                    self.controller.move_to_xy_slow(x1, 8 * SIZE_OFF_SQUARE);
-                    self.controller.move_to_xy_slow(x2, 8 * SIZE_OFF_SQUARE);
-                    self.controller.down();
-                    self.move_to_square_fast(Square::H8);
-                    self.controller.up();
-                    self.move_to_square_slow(Square::F8);
-                    self.controller.down();
-                    self.controller.move_to_xy_fast(x2, 8 * SIZE_OFF_SQUARE);
-                    self.controller.up();
-                    self.controller.move_to_xy_slow(x2, y2 + Y_PLACEMENT_OFFSET);
-                    self.controller.down();
-                } else {
-                    self.move_to_square_fast(Square::E8);
-                    self.controller.up();
-                    self.controller.wait();
-                    let (x1, _) = get_square_coordinates(Square::E8);
-                    let (x2, y2) = get_square_coordinates(Square::C8);
-                    self.controller.move_to_xy_slow(x1, 8 * SIZE_OFF_SQUARE);
-                    self.controller.move_to_xy_slow(x2, 8 * SIZE_OFF_SQUARE);
-                    self.controller.down();
-                    self.move_to_square_fast(Square::A8);
-                    self.controller.up();
-                    self.move_to_square_slow(Square::D8);
-                    self.controller.down();
-                    self.controller.move_to_xy_fast(x2, 8 * SIZE_OFF_SQUARE);
-                    self.controller.up();
-                    self.controller.move_to_xy_slow(x2, y2 + Y_PLACEMENT_OFFSET);
-                    self.controller.down();
+            // the promoted piece may already stand on the target
+            let mut standing = obstacles(&self.position, &[m.origin(), m.target()]);
+            standing.push(square_center(m.target()));
+            match self.plan_capture(
+                &mut planned.graveyard,
+                &mut planned.substitutes,
+                m.origin(),
+                white,
+                &standing,
+            ) {
+                Ok((slot, carry)) => {
+                    planned.played.pawn = Some(slot);
+                    planned.carries.push(carry);
                 }
+                Err(notice) => planned.notices.push(notice),
             }
+        } else if m.is_castle() {
+            let (king, king_to, rook, rook_to) = match (white, m.is_king_side_castle()) {
+                (true, true) => (Square::E1, Square::G1, Square::H1, Square::F1),
+                (true, false) => (Square::E1, Square::C1, Square::A1, Square::D1),
+                (false, true) => (Square::E8, Square::G8, Square::H8, Square::F8),
+                (false, false) => (Square::E8, Square::C8, Square::A8, Square::D8),
+            };
+            // the king is carried along the edge of the board, past the rook
+            let edge_rank = if white { 0.0 } else { 8.0 };
+            let edge = format!("the edge of {}", king_to);
+            let (file1, _) = square_center(king);
+            let (file2, _) = square_center(king_to);
+            let (x2, y2) = g.get_square_coordinates(king_to);
+            planned.carries.push(Carry {
+                piece: letter(king),
+                from: king.to_string(),
+                to: edge.clone(),
+                start: g.get_square_coordinates(king),
+                path: vec![
+                    g.board_to_xy((file1, edge_rank)),
+                    g.board_to_xy((file2, edge_rank)),
+                ],
+                settle: false,
+            });
+            planned.carries.push(Carry {
+                piece: letter(rook),
+                from: rook.to_string(),
+                to: rook_to.to_string(),
+                start: g.get_square_coordinates(rook),
+                path: vec![g.get_square_coordinates(rook_to)],
+                settle: false,
+            });
+            planned.carries.push(Carry {
+                piece: letter(king),
+                from: edge,
+                to: king_to.to_string(),
+                start: g.board_to_xy((file2, edge_rank)),
+                path: vec![(x2, y2 + g.y_placement_offset)],
+                settle: false,
+            });
         } else {
-            match self.position.get_square(m.origin()).piece_type() {
+            let (ox, oy) = g.get_square_coordinates(m.origin());
+            let (path, settle) = match self.position.get_square(m.origin()).piece_type() {
                 PieceType::KNIGHT => {
-                    let (ox, oy) = get_square_coordinates(m.origin());
-                    let (tx, ty) = get_square_coordinates(m.target());
+                    // the knight passes between the pieces
+                    let (tx, ty) = g.get_square_coordinates(m.target());
                     let dx = tx - ox;
                     let dy = ty - oy;
-                    self.move_to_square_fast(m.origin());
-                    self.controller.up();
-                    self.controller.wait();
-                    if dx.abs() < dy.abs() {
-                        self.controller.move_to_xy_slow(ox + dx / 2, oy);
-                        self.controller.wait();
-                        self.controller.move_to_xy_slow(ox + dx / 2, oy + dy);
-                        self.controller.wait();
-                        self.controller.move_to_xy_slow(ox + dx, oy + dy);
-                        self.controller.wait();
+                    let path = if dx.abs() < dy.abs() {
+                        vec![
+                            (ox + dx / 2, oy),
+                            (ox + dx / 2, oy + dy),
+                            (ox + dx, oy + dy),
+                        ]
                     } else {
-                        self.controller.move_to_xy_slow(ox, oy + dy / 2);
-                        self.controller.wait();
-                        self.controller.move_to_xy_slow(ox + dx, oy + dy / 2);
-                        self.controller.wait();
-                        self.controller.move_to_xy_slow(ox + dx, oy + dy);
-                        self.controller.wait();
-                    }
-                    self.controller.down();
-                    self.controller.wait();
+                        vec![
+                            (ox, oy + dy / 2),
+                            (ox + dx, oy + dy / 2),
+                            (ox + dx, oy + dy),
+                        ]
+                    };
+                    (path, true)
                 }
                 _ => {
-                    self.move_to_square_fast(m.origin());
-                    self.controller.up();
-                    self.controller.wait();
-                    let (x, mut y) = get_square_coordinates(m.target());
-                    y += Y_PLACEMENT_OFFSET;
-                    self.controller.move_to_xy_slow(x, y);
-                    self.controller.down();
+                    // the captured piece is already in the graveyard, even though it is still
+                    // part of the position
+                    let obstacles =
+                        obstacles(&self.position, &[m.origin(), m.target(), capture_square]);
+                    let points = plan_path(
+                        square_center(m.origin()),
+                        square_center(m.target()),
+                        &obstacles,
+                    );
+                    let mut path: Vec<(i32, i32)> =
+                        points.iter().map(|&p| g.board_to_xy(p)).collect();
+                    if let Some(target) = path.last_mut() {
+                        target.1 += g.y_placement_offset;
+                    }
+                    (path, false)
+                }
+            };
+            planned.carries.push(Carry {
+                piece: letter(m.origin()),
+                from: m.origin().to_string(),
+                to: m.target().to_string(),
+                start: (ox, oy),
+                path,
+                settle,
+            });
+        }
+        for (sq, _) in &mut planned.substitutes {
+            if *sq == m.origin() {
+                *sq = m.target();
+            }
+        }
+        planned
+    }
+
+    /// Plans to carry the piece on the square into the first free slot of the tray of the given
+    /// colour, past the pieces in `obstacles`, and updates the trays and the substitutes
+    /// accordingly. Returns the slot and the carry. If the tray is full the piece stays where it
+    /// is and the players are asked to take it away.
+    fn plan_capture(
+        &self,
+        graveyard: &mut Graveyard,
+        substitutes: &mut Vec<(Square, PieceType)>,
+        sq: Square,
+        white: bool,
+        obstacles: &[Point],
+    ) -> Result<(usize, Carry), Notice> {
+        let piece = match substitutes.iter().position(|&(s, _)| s == sq) {
+            Some(i) => substitutes.remove(i).1,
+            None => self.position.get_square(sq).piece_type(),
+        };
+        let slot = graveyard
+            .add(white, piece)
+            .ok_or(Notice::TrayFull { square: sq, white })?;
+        let (start, path) = self.route(
+            Place::Square(index(sq)),
+            Place::Slot(white, slot),
+            obstacles,
+        );
+        let carry = Carry {
+            piece: arrange::piece_letter(white, piece),
+            from: sq.to_string(),
+            to: tray_label(white, slot),
+            start,
+            path,
+            settle: false,
+        };
+        Ok((slot, carry))
+    }
+
+    /// Carries the pieces of a move that aren't at their destination yet or, if
+    /// [`Progress::back`] is set, the ones that are back to their origin. Every sub-step is
+    /// journaled before it starts and every carry once it is done, cf. [`crate::journal`].
+    fn carry_pieces(&mut self, progress: &mut Progress) -> Result<(), RoboChessError> {
+        loop {
+            let carry = if progress.back {
+                match progress.done.checked_sub(1) {
+                    Some(i) => progress.carries[i].reversed(),
+                    None => break,
+                }
+            } else {
+                match progress.carries.get(progress.done) {
+                    Some(carry) => carry.clone(),
+                    None => break,
+                }
+            };
+            self.save_phase(progress, Some(Phase::Pick));
+            self.controller
+                .move_to_xy_fast(carry.start.0, carry.start.1)?;
+            self.controller.up();
+            self.controller.wait();
+            self.save_phase(progress, Some(Phase::Carry));
+            let carried = carry.path.iter().try_for_each(|&(x, y)| {
+                self.controller.move_to_xy_slow(x, y)?;
+                if carry.settle {
                     self.controller.wait();
                 }
+                Ok(())
+            });
+            if let Err(e) = carried {
+                // the next move of the carriage must not drag the piece along
+                self.controller.down();
+                return Err(e);
             }
+            self.save_phase(progress, Some(Phase::Place));
+            self.controller.down();
+            self.controller.wait();
+
+            if progress.back {
+                progress.done -= 1;
+            } else {
+                progress.done += 1;
+            }
+            self.save_phase(progress, None);
         }
+        Ok(())
+    }
+
+    /// Journals the sub-step of the carry in progress.
+    fn save_phase(&self, progress: &mut Progress, phase: Option<Phase>) {
+        progress.phase = phase;
+        self.save_journal(Some(Task::Move(progress.clone())));
+    }
+
+    /// Updates the game once all pieces of a planned move have been carried.
+    fn finish_move(&mut self, planned: PlannedMove) -> Vec<Notice> {
+        let mut played = planned.played;
+        let m = played.m;
+        self.graveyard = planned.graveyard;
+        self.substitutes = planned.substitutes;
+        self.reserve_white_used = planned.reserve_white_used;
+        self.reserve_black_used = planned.reserve_black_used;
+        self.awaited_piece = planned.awaited_piece;
         self.position.make_bit_move(m);
-        true
+        played.time = pgn::now();
+        self.history.push(played);
+        if m.is_capture() || m.is_promotion() {
+            self.save_graveyard();
+        }
+        self.save_journal(None);
+        planned.notices
     }
 
-    pub fn capture_piece(&mut self, sq: Square, color: Color) {
-        self.move_to_square_fast(sq);
+    /// Finishes the move the robot was interrupted in, cf. [`RoboChess::interrupted`] and
+    /// [`crate::journal`]. A piece that was on its way has to be put on its destination by hand to
+    /// roll the move forward, or on its origin to roll it back. Returns what the players have to
+    /// be told about the move. If the gantry fails again the move stays interrupted with the
+    /// progress made so far.
+    pub fn recover(&mut self, roll: Roll) -> Result<Vec<Notice>, RoboChessError> {
+        let mut progress = self.interrupted.clone().ok_or_else(|| {
+            RoboChessError::Protocol("the robot wasn't interrupted in a move".to_string())
+        })?;
+        let m = self
+            .position
+            .generate_legal_moves()
+            .into_iter()
+            .find(|&m| coordinate_notation(m) == progress.m)
+            .ok_or_else(|| RoboChessError::IllegalMove(progress.m.clone()))?;
+        let (done, on_the_way) = progress.settled();
+        progress.phase = None;
+        let planned = match roll {
+            Roll::Forward => {
+                progress.done = on_the_way.map_or(done, |i| i + 1);
+                progress.back = false;
+                Some(self.plan_move(m))
+            }
+            Roll::Back => {
+                progress.done = on_the_way.unwrap_or(done);
+                progress.back = true;
+                None
+            }
+        };
+        if let Err(e) = self.carry_pieces(&mut progress) {
+            self.interrupted = Some(progress);
+            return Err(e);
+        }
+        self.interrupted = None;
+        match planned {
+            Some(planned) => Ok(self.finish_move(planned)),
+            None => {
+                self.save_journal(None);
+                Ok(Vec::new())
+            }
+        }
+    }
+
+    /// Returns the record of the game since the position was set up.
+    pub fn record(&self, white: &str, black: &str) -> Record {
+        let result = if self.position.is_checkmate() {
+            if self.position.side_to_move() == Color::WHITE {
+                "0-1"
+            } else {
+                "1-0"
+            }
+        } else if self.position.is_draw() {
+            "1/2-1/2"
+        } else {
+            "*"
+        };
+        Record {
+            white: white.to_string(),
+            black: black.to_string(),
+            fen: self
+                .history
+                .first()
+                .map_or_else(|| self.position.to_fen(), |played| played.fen.clone()),
+            started: self.started,
+            moves: self
+                .history
+                .iter()
+                .map(|played| (played.san.clone(), played.time))
+                .collect(),
+            result,
+        }
+    }
+
+    /// Returns the type of the piece that physically stands on the square, which is not the one
+    /// of the position if it is a substitute.
+    fn physical_piece(&self, sq: Square) -> PieceType {
+        match self.substitutes.iter().find(|&&(s, _)| s == sq) {
+            Some(&(_, piece)) => piece,
+            None => self.position.get_square(sq).piece_type(),
+        }
+    }
+
+    /// Returns the pieces that physically stand on the board, i.e. with the substitutes instead
+    /// of the promoted pieces.
+    pub fn physical_board(&self) -> Result<Board, RoboChessError> {
+        let fen = self.position.to_fen();
+        let mut board = parse_board(&fen).ok_or(RoboChessError::InvalidFen(fen))?;
+        for &(sq, piece) in &self.substitutes {
+            let i = index(sq);
+            let white = !matches!(board[i], Some(c) if c.is_ascii_lowercase());
+            board[i] = Some(arrange::piece_letter(white, piece));
+        }
+        Ok(board)
+    }
+
+    /// Plans how to arrange the pieces on the board and in the trays for the position given as a
+    /// complete FEN, cf. [`arrange`]. Returns the reason if that's not possible.
+    pub fn plan_arrangement(&self, fen: &str) -> Result<Arrangement, RoboChessError> {
+        self.check_interrupted()?;
+        if let Some(sq) = self.awaited_piece {
+            return Err(RoboChessError::Busy(format!(
+                "the promoted piece on {} has not been confirmed yet",
+                sq
+            )));
+        }
+        let position =
+            Position::from_fen(fen).map_err(|_| RoboChessError::InvalidFen(fen.to_string()))?;
+        let fen = position.to_fen();
+        let target = parse_board(&fen).ok_or_else(|| RoboChessError::InvalidFen(fen.clone()))?;
+        let board = self.physical_board()?;
+        let steps =
+            arrange::plan(&board, &self.graveyard, &target).map_err(RoboChessError::Arrangement)?;
+        Ok(Arrangement {
+            fen,
+            steps,
+            done: 0,
+            board,
+            substitutes: Vec::new(),
+            history: 0,
+            reserve_white_used: self.reserve_white_used,
+            reserve_black_used: self.reserve_black_used,
+        })
+    }
+
+    /// Plans how to take back the last moves: the pieces are carried back, captured pieces return
+    /// from the tray and promoted pawns replace the promoted pieces again, which go into the tray
+    /// or, if they were spare queens, back to their place. Returns the reason if that's not
+    /// possible.
+    pub fn plan_undo(&self, moves: usize) -> Result<Arrangement, RoboChessError> {
+        self.check_interrupted()?;
+        if let Some(sq) = self.awaited_piece {
+            return Err(RoboChessError::Busy(format!(
+                "the promoted piece on {} has not been confirmed yet",
+                sq
+            )));
+        }
+        if moves == 0 {
+            return Err(RoboChessError::Undo(
+                "at least one move has to be taken back".to_string(),
+            ));
+        }
+        if self.history.is_empty() {
+            return Err(RoboChessError::Undo(
+                "there is no move to take back".to_string(),
+            ));
+        }
+        if moves > self.history.len() {
+            return Err(RoboChessError::Undo(format!(
+                "can't take back {} moves, the game only has {}",
+                moves,
+                self.history.len()
+            )));
+        }
+        let board = self.physical_board()?;
+        let mut undo = Undo {
+            board,
+            graveyard: self.graveyard.clone(),
+            steps: Vec::new(),
+            reserve_white_used: self.reserve_white_used,
+            reserve_black_used: self.reserve_black_used,
+        };
+        for played in self.history.iter().rev().take(moves) {
+            undo.take_back(played).map_err(RoboChessError::Undo)?;
+        }
+        let history = self.history.len() - moves;
+        let first = &self.history[history];
+        Ok(Arrangement {
+            fen: first.fen.clone(),
+            steps: undo.steps,
+            done: 0,
+            board,
+            substitutes: first.substitutes.clone(),
+            history,
+            reserve_white_used: undo.reserve_white_used,
+            reserve_black_used: undo.reserve_black_used,
+        })
+    }
+
+    /// Carries out the next step of the arrangement and sets up its position once all steps are
+    /// done. Returns whether the arrangement is complete. If the gantry fails the step is not
+    /// counted as done.
+    pub fn arrange_step(&mut self, arrangement: &mut Arrangement) -> Result<bool, RoboChessError> {
+        if arrangement.done == 0 && !arrangement.steps.is_empty() {
+            self.save_journal(Some(Task::Arrangement(arrangement.fen.clone())));
+        }
+        if let Some(&step) = arrangement.steps.get(arrangement.done) {
+            let obstacles: Vec<Point> = (0..64)
+                .filter(|&i| arrangement.board[i].is_some())
+                .filter(|&i| Place::Square(i) != step.from && Place::Square(i) != step.to)
+                .map(arrange::center)
+                .collect();
+            self.carry(step.from, step.to, &obstacles)?;
+            step.apply(&mut arrangement.board, &mut self.graveyard);
+            arrangement.done += 1;
+            self.save_graveyard();
+        }
+        if arrangement.done < arrangement.steps.len() {
+            return Ok(false);
+        }
+        // the fen has been parsed when the arrangement was planned
+        if let Ok(position) = Position::from_fen(&arrangement.fen) {
+            self.position = position;
+        }
+        self.substitutes = arrangement.substitutes.clone();
+        self.reserve_white_used = arrangement.reserve_white_used;
+        self.reserve_black_used = arrangement.reserve_black_used;
+        self.history.truncate(arrangement.history);
+        if self.history.is_empty() {
+            self.started = pgn::now();
+        }
+        self.save_journal(None);
+        Ok(true)
+    }
+
+    /// Returns where a carried piece enters or leaves the board: the centre of a square or the
+    /// corner at the edge of the board next to a slot of a tray or, for a spare queen, next to
+    /// the first slot.
+    fn entry(place: Place) -> Point {
+        match place {
+            Place::Square(i) => arrange::center(i),
+            Place::Slot(true, slot) => (8.0, (slot as f32 / 2.0 + 0.25).round().min(8.0)),
+            Place::Slot(false, slot) => (0.0, (8.25 - slot as f32 / 2.0).round().max(0.0)),
+            Place::Reserve(true, _) => (8.0, 0.0),
+            Place::Reserve(false, _) => (0.0, 8.0),
+        }
+    }
+
+    /// Returns the waypoints from the spare queen at `reserve` to `to`. It is carried around the
+    /// board, beyond the trays and the other pieces.
+    fn around_the_board(
+        &self,
+        white: bool,
+        (x1, y1): (i32, i32),
+        to: (i32, i32),
+    ) -> Vec<(i32, i32)> {
+        let g = &self.geometry;
+        let (x2, y2) = to;
+        if white {
+            vec![(g.min_x, y1), (g.min_x, g.max_y), (x2, g.max_y), (x2, y2)]
+        } else {
+            vec![(x1, y1), (x1, g.min_y), (x2, g.min_y), (x2, y2)]
+        }
+    }
+
+    /// Returns where a piece is picked up at `from` and the waypoints to carry it to `to` without
+    /// touching the pieces in `obstacles`.
+    fn route(&self, from: Place, to: Place, obstacles: &[Point]) -> ((i32, i32), Vec<(i32, i32)>) {
+        let g = &self.geometry;
+        let xy = |place| match place {
+            Place::Square(i) => g.board_to_xy(arrange::center(i)),
+            Place::Slot(white, slot) => g.slot_coordinates(white, slot),
+            Place::Reserve(_, xy) => xy,
+        };
+        let start = xy(from);
+        let mut end = xy(to);
+        if let Place::Square(_) = to {
+            end.1 += g.y_placement_offset;
+        }
+        match (from, to) {
+            (Place::Reserve(white, reserve), _) => {
+                return (start, self.around_the_board(white, reserve, end));
+            }
+            (_, Place::Reserve(white, reserve)) => {
+                // back the way it came
+                let mut path = self.around_the_board(white, reserve, start);
+                path.pop();
+                path.reverse();
+                if path.last() != Some(&end) {
+                    path.push(end);
+                }
+                return (start, path);
+            }
+            _ => {}
+        }
+        let mut waypoints = Vec::new();
+        if let Place::Slot(..) = from {
+            waypoints.push(g.board_to_xy(Self::entry(from)));
+        }
+        for p in plan_path(Self::entry(from), Self::entry(to), obstacles) {
+            // a path from a corner may start at the corner itself
+            let xy = g.board_to_xy(p);
+            if waypoints.last() != Some(&xy) {
+                waypoints.push(xy);
+            }
+        }
+        match (to, waypoints.last_mut()) {
+            (Place::Square(_), Some(last)) => *last = end,
+            _ => waypoints.push(end),
+        }
+        (start, waypoints)
+    }
+
+    /// Carries a piece from one place to another without touching the pieces in `obstacles`.
+    fn carry(&mut self, from: Place, to: Place, obstacles: &[Point]) -> Result<(), RoboChessError> {
+        let (start, waypoints) = self.route(from, to, obstacles);
+        self.controller.move_to_xy_fast(start.0, start.1)?;
         self.controller.up();
         self.controller.wait();
-        let (mut x, mut y) = get_square_coordinates(sq);
-
-        // capture white piece
-        if !color == Color::WHITE {
-            y += SIZE_OFF_SQUARE / 2;
-            self.controller.move_to_xy_slow(x, y);
-            x = X_OFFSET - SIZE_OFF_SQUARE / 4;
-            self.controller.move_to_xy_slow(x, y);
-            y = self.captured_pieces_white.len() as i32 * SIZE_OFF_SQUARE / 2 + SIZE_OFF_SQUARE / 4;
-            self.captured_pieces_white
-                .push(self.position.get_square(sq));
-        } else {
-            // capture black piece
-            y -= SIZE_OFF_SQUARE / 2;
-            self.controller.move_to_xy_slow(x, y);
-            x = X_OFFSET + 33 * SIZE_OFF_SQUARE / 4;
-            self.controller.move_to_xy_slow(x, y);
-            y = 8 * SIZE_OFF_SQUARE - self.captured_pieces_white.len() as i32 * SIZE_OFF_SQUARE / 2
-                + SIZE_OFF_SQUARE / 4;
-            self.captured_pieces_black
-                .push(self.position.get_square(sq));
-        }
-        self.controller.move_to_xy_slow(x, y);
+        let carried = waypoints
+            .into_iter()
+            .try_for_each(|(x, y)| self.controller.move_to_xy_slow(x, y));
         self.controller.down();
+        carried?;
         self.controller.wait();
+        Ok(())
     }
 
-    pub fn move_to_square_slow(&mut self, sq: Square) {
-        let (x, y) = get_square_coordinates(sq);
-        self.controller.move_to_xy_slow(x, y);
+    /// Moves the magnet by the given number of steps, but never out of bounds. Returns the new
+    /// position.
+    pub fn jog(&mut self, dx: i32, dy: i32) -> Result<(i32, i32), RoboChessError> {
+        let g = &self.geometry;
+        let (x, y) = self.controller.position();
+        let x = (x + dx).clamp(g.min_x, g.max_x);
+        let y = (y + dy).clamp(g.min_y, g.max_y);
+        self.controller.move_to_xy_slow(x, y)?;
+        Ok((x, y))
     }
 
-    pub fn move_to_square_fast(&mut self, sq: Square) {
-        let (x, y) = get_square_coordinates(sq);
-        self.controller.move_to_xy_fast(x, y);
+    /// Moves the magnet to the given coordinates. Fails with [`RoboChessError::OutOfBounds`] if
+    /// they are out of bounds.
+    pub fn move_to_xy(&mut self, x: i32, y: i32) -> Result<(), RoboChessError> {
+        self.controller.move_to_xy_slow(x, y)
     }
 }
@@ -0,0 +1,602 @@
+//! Regression tests for [`RoboChess::make_move`] and [`RoboChess::arrange_step`]. Every test plays
+//! moves, takes them back or arranges a position on a [`SimulatedGantry`] and compares the
+//! resulting waypoints against a golden file in `controller/golden`. Run the tests with
+//! `UPDATE_GOLDEN=1` to rewrite the golden files after an intended change of the trajectories and
+//! review the diff before committing it.
+
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::PathBuf;
+
+use chers::{ParsedMove, PieceType, Position, Square};
+
+use super::{Notice, RoboChess};
+use crate::calibration::Transform;
+use crate::config::{Geometry, PromotionPolicy};
+use crate::error::RoboChessError;
+use crate::graveyard::Graveyard;
+use crate::journal::{Phase, Progress, Roll, Task};
+use crate::simulator::{Command, SimulatedGantry};
+
+const START: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+const CASTLE_WHITE: &str = "r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1";
+const CASTLE_BLACK: &str = "r3k2r/8/8/8/8/8/8/R3K2R b KQkq - 0 1";
+
+/// Renders the commands as one `x y magnet` waypoint per line.
+fn render(commands: &[Command]) -> String {
+    let (mut x, mut y, mut magnet) = (0, 0, "down");
+    let mut res = String::new();
+    for &command in commands {
+        match command {
+            Command::Home => {
+                x = 0;
+                y = 0;
+            }
+            Command::MoveSlow(tx, ty) | Command::MoveFast(tx, ty) => {
+                x = tx;
+                y = ty;
+            }
+            Command::Up => magnet = "up",
+            Command::Down => magnet = "down",
+        }
+        writeln!(res, "{} {} {}", x, y, magnet).unwrap();
+    }
+    res
+}
+
+fn setup(fen: &str) -> RoboChess<SimulatedGantry> {
+    setup_with(Geometry::default(), fen)
+}
+
+fn setup_with(geometry: Geometry, fen: &str) -> RoboChess<SimulatedGantry> {
+    let mut robochess = RoboChess::with_gantry(SimulatedGantry::new(&geometry, false), geometry);
+    robochess.position = Position::from_fen(fen).unwrap();
+    robochess
+}
+
+/// Plays `m` and compares the trajectory with `golden/<name>.txt`.
+fn check(name: &str, mut robochess: RoboChess<SimulatedGantry>, m: &str) {
+    let start = robochess.controller.commands().len();
+    robochess.make_move(m).unwrap();
+    compare(name, &robochess.controller.commands()[start..]);
+}
+
+/// Compares the trajectory with `golden/<name>.txt`.
+fn compare(name: &str, commands: &[Command]) {
+    let actual = render(commands);
+
+    let path: PathBuf = [
+        env!("CARGO_MANIFEST_DIR"),
+        "golden",
+        &format!("{}.txt", name),
+    ]
+    .iter()
+    .collect();
+    if env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::write(&path, &actual).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(&path).unwrap_or_else(|_| {
+        panic!(
+            "missing {}, run with UPDATE_GOLDEN=1 to create it",
+            path.display()
+        )
+    });
+    assert_eq!(actual, expected, "trajectory of {} changed", name);
+}
+
+#[test]
+fn quiet_move() {
+    check("quiet_move", setup(START), "e2e4");
+}
+
+#[test]
+fn knight_move() {
+    check("knight_move", setup(START), "g1f3");
+}
+
+#[test]
+fn white_captures() {
+    let fen = "rnbqkbnr/ppp1pppp/8/3p4/4P3/8/PPPP1PPP/RNBQKBNR w KQkq d6 0 2";
+    check("white_captures", setup(fen), "e4d5");
+}
+
+#[test]
+fn black_captures() {
+    let fen = "rnbqkbnr/ppp1pppp/8/3p4/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 2";
+    check("black_captures", setup(fen), "d5e4");
+}
+
+#[test]
+fn capture_detour() {
+    // the straight line from d4 to the black tray passes the pawn on c5
+    let fen = "4k3/8/8/2P5/3n4/8/8/3QK3 w - - 0 1";
+    check("capture_detour", setup(fen), "d1d4");
+}
+
+#[test]
+fn en_passant() {
+    let fen = "rnbqkbnr/ppp1p1pp/8/3pPp2/8/8/PPPP1PPP/RNBQKBNR w KQkq f6 0 3";
+    check("en_passant", setup(fen), "e5f6");
+}
+
+#[test]
+fn white_promotion() {
+    let mut robochess = setup("8/4P3/8/8/8/8/k7/4K3 w - - 0 1");
+    robochess.graveyard.add(true, PieceType::QUEEN);
+    check("white_promotion", robochess, "e7e8q");
+}
+
+#[test]
+fn white_promotion_with_capture() {
+    let mut robochess = setup("3r4/4P3/8/8/8/8/k7/4K3 w - - 0 1");
+    robochess.graveyard.add(true, PieceType::KNIGHT);
+    check("white_promotion_with_capture", robochess, "e7d8n");
+}
+
+#[test]
+fn black_promotion() {
+    let mut robochess = setup("4k3/8/8/8/8/8/4p3/K7 b - - 0 1");
+    robochess.graveyard.add(false, PieceType::QUEEN);
+    check("black_promotion", robochess, "e2e1q");
+}
+
+#[test]
+fn promotion_without_tray_piece() {
+    let mut robochess = setup("8/4P3/8/8/8/8/k7/4K3 w - - 0 1");
+    assert_eq!(
+        robochess.make_move("e7e8n").unwrap(),
+        vec![Notice::MissingPromotionPiece {
+            square: Square::E8,
+            white: true,
+            piece: PieceType::KNIGHT,
+        }]
+    );
+    assert_eq!(robochess.awaited_piece, Some(Square::E8));
+    assert!(robochess.confirm_placement());
+    assert!(!robochess.confirm_placement());
+}
+
+#[test]
+fn promotion_with_substitute() {
+    let mut robochess = setup("8/4P3/8/8/8/8/k7/4K3 w - - 0 1");
+    robochess.promotion.policy = PromotionPolicy::Substitute;
+    robochess.graveyard.add(true, PieceType::KNIGHT);
+    robochess.graveyard.add(true, PieceType::ROOK);
+    check("promotion_with_substitute", robochess, "e7e8q");
+}
+
+#[test]
+fn promotion_from_reserve() {
+    let mut robochess = setup("4k3/8/8/8/8/8/4p3/K7 b - - 0 1");
+    robochess.promotion.reserve_black = Some((2300, 100));
+    check("promotion_from_reserve", robochess, "e2e1q");
+}
+
+#[test]
+fn captured_substitute_returns_to_the_tray() {
+    let mut robochess = setup("8/4P3/8/8/8/8/k7/4K3 w - - 0 1");
+    robochess.promotion.policy = PromotionPolicy::Substitute;
+    robochess.graveyard.add(true, PieceType::ROOK);
+    assert_eq!(
+        robochess.make_move("e7e8q").unwrap(),
+        vec![Notice::Substitute {
+            square: Square::E8,
+            white: true,
+            piece: PieceType::QUEEN,
+            substitute: PieceType::ROOK,
+        }]
+    );
+    assert_eq!(robochess.awaited_piece, None);
+    robochess.make_move("a2b2").unwrap();
+    robochess.make_move("e8e5").unwrap();
+    assert_eq!(robochess.substitutes, vec![(Square::E5, PieceType::ROOK)]);
+    robochess.make_move("b2b3").unwrap();
+    let mut graveyard = robochess.graveyard.clone();
+    let mut substitutes = robochess.substitutes.clone();
+    let (slot, carry) = robochess
+        .plan_capture(&mut graveyard, &mut substitutes, Square::E5, true, &[])
+        .unwrap();
+    assert_eq!(slot, 1);
+    assert_eq!(carry.piece, 'R');
+    assert!(substitutes.is_empty());
+    // the pawn of the promotion is in the first slot, the rook took the free one after it
+    assert_eq!(
+        &graveyard.slots(true)[..2],
+        &[Some(PieceType::PAWN), Some(PieceType::ROOK)]
+    );
+}
+
+#[test]
+fn second_black_capture() {
+    let fen = "rnbqkbnr/ppp1pppp/8/3p4/4P3/8/PPPP1PPP/RNBQKBNR w KQkq d6 0 2";
+    let mut robochess = setup(fen);
+    robochess.graveyard.add(false, PieceType::PAWN);
+    check("second_black_capture", robochess, "e4d5");
+}
+
+#[test]
+fn full_tray() {
+    let fen = "rnbqkbnr/ppp1pppp/8/3p4/4P3/8/PPPP1PPP/RNBQKBNR w KQkq d6 0 2";
+    let mut robochess = setup(fen);
+    while robochess.graveyard.add(false, PieceType::PAWN).is_some() {}
+    assert_eq!(
+        robochess.make_move("e4d5").unwrap(),
+        vec![Notice::TrayFull {
+            square: Square::D5,
+            white: false,
+        }]
+    );
+}
+
+#[test]
+fn promotion_needs_a_piece() {
+    let mut robochess = setup("8/4P3/8/8/8/8/k7/4K3 w - - 0 1");
+    assert!(matches!(
+        robochess.make_move("e7e8"),
+        Err(RoboChessError::IllegalMove(_))
+    ));
+}
+
+#[test]
+fn white_king_side_castle() {
+    check("white_king_side_castle", setup(CASTLE_WHITE), "e1g1");
+}
+
+#[test]
+fn white_queen_side_castle() {
+    check("white_queen_side_castle", setup(CASTLE_WHITE), "e1c1");
+}
+
+#[test]
+fn black_king_side_castle() {
+    check("black_king_side_castle", setup(CASTLE_BLACK), "e8g8");
+}
+
+#[test]
+fn black_queen_side_castle() {
+    check("black_queen_side_castle", setup(CASTLE_BLACK), "e8c8");
+}
+
+/// Plays a promotion with a capture, a capture, a castling and a promotion from the other tray and
+/// compares the trajectory with `golden/<name>.txt`.
+fn check_geometry(name: &str, geometry: Geometry) {
+    assert_eq!(geometry.unreachable_point(), None);
+    let mut robochess = setup_with(geometry, "r3k3/1P1N4/8/8/8/8/1p6/4K2R w K - 0 1");
+    robochess.graveyard.add(true, PieceType::QUEEN);
+    robochess.graveyard.add(false, PieceType::QUEEN);
+    for m in &["b7a8q", "e8d7", "e1g1", "b2b1q"] {
+        robochess.make_move(m).unwrap();
+    }
+    compare(name, robochess.controller.commands());
+}
+
+#[test]
+fn offset_board() {
+    let geometry = Geometry {
+        x_offset: 150,
+        y_offset: 120,
+        min_x: 10,
+        max_x: 2500,
+        min_y: 30,
+        max_y: 2400,
+        ..Geometry::default()
+    };
+    check_geometry("offset_board", geometry);
+}
+
+#[test]
+fn calibrated_board() {
+    // a board turned slightly counterclockwise
+    let geometry = Geometry {
+        calibration: Some(Transform {
+            xx: -262.0,
+            xy: 6.0,
+            x0: 2250.0,
+            yx: 5.0,
+            yy: 263.0,
+            y0: 20.0,
+        }),
+        ..Geometry::default()
+    };
+    check_geometry("calibrated_board", geometry);
+}
+
+#[test]
+fn reset_after_capture() {
+    // 1. e4 d5 2. exd5
+    let fen = "rnbqkbnr/ppp1pppp/8/3P4/8/8/PPPP1PPP/RNBQKBNR b KQkq - 0 2";
+    let mut robochess = setup(fen);
+    robochess.graveyard.add(false, PieceType::PAWN);
+    let start = robochess.controller.commands().len();
+    let mut arrangement = robochess.plan_arrangement(START).unwrap();
+    assert_eq!(arrangement.steps.len(), 2);
+    assert!(!robochess.arrange_step(&mut arrangement).unwrap());
+    assert!(robochess.arrange_step(&mut arrangement).unwrap());
+    assert_eq!(robochess.position.to_fen(), START);
+    assert_eq!(robochess.graveyard, Graveyard::new());
+    compare(
+        "reset_after_capture",
+        &robochess.controller.commands()[start..],
+    );
+}
+
+/// Plays the moves, takes back the last `moves` of them and returns the trajectory of the undo.
+fn undo(robochess: &mut RoboChess<SimulatedGantry>, played: &[&str], moves: usize) -> Vec<Command> {
+    for m in played {
+        robochess.make_move(m).unwrap();
+    }
+    let start = robochess.controller.commands().len();
+    let mut arrangement = robochess.plan_undo(moves).unwrap();
+    while !robochess.arrange_step(&mut arrangement).unwrap() {}
+    robochess.controller.commands()[start..].to_vec()
+}
+
+#[test]
+fn undo_capture() {
+    let fen = "rnbqkbnr/ppp1pppp/8/3p4/4P3/8/PPPP1PPP/RNBQKBNR w KQkq d6 0 2";
+    let mut robochess = setup(fen);
+    let commands = undo(&mut robochess, &["e4d5"], 1);
+    assert_eq!(robochess.position.to_fen(), fen);
+    assert_eq!(robochess.graveyard, Graveyard::new());
+    assert!(robochess.history.is_empty());
+    compare("undo_capture", &commands);
+}
+
+#[test]
+fn undo_castle() {
+    let mut robochess = setup(CASTLE_WHITE);
+    let commands = undo(&mut robochess, &["e1c1"], 1);
+    assert_eq!(robochess.position.to_fen(), CASTLE_WHITE);
+    compare("undo_castle", &commands);
+}
+
+#[test]
+fn undo_promotion() {
+    let fen = "3r4/4P3/8/8/8/8/k7/4K3 w - - 0 1";
+    let mut robochess = setup(fen);
+    robochess.graveyard.add(true, PieceType::QUEEN);
+    let commands = undo(&mut robochess, &["e7d8q"], 1);
+    assert_eq!(robochess.position.to_fen(), fen);
+    let mut graveyard = Graveyard::new();
+    graveyard.add(true, PieceType::QUEEN);
+    assert_eq!(robochess.graveyard, graveyard);
+    compare("undo_promotion", &commands);
+}
+
+#[test]
+fn undo_promotion_from_reserve() {
+    let fen = "4k3/8/8/8/8/8/4p3/K7 b - - 0 1";
+    let mut robochess = setup(fen);
+    robochess.promotion.reserve_black = Some((2300, 100));
+    robochess.make_move("e2e1q").unwrap();
+    assert!(robochess.reserve_black_used);
+    let commands = undo(&mut robochess, &[], 1);
+    assert_eq!(robochess.position.to_fen(), fen);
+    // the queen is back in its place and is used for the next promotion again
+    assert!(!robochess.reserve_black_used);
+    assert_eq!(robochess.graveyard, Graveyard::new());
+    compare("undo_promotion_from_reserve", &commands);
+    let start = robochess.controller.commands().len();
+    robochess.make_move("e2e1q").unwrap();
+    compare(
+        "promotion_from_reserve",
+        &robochess.controller.commands()[start..],
+    );
+}
+
+#[test]
+fn undo_en_passant() {
+    let fen = "rnbqkbnr/pppppppp/8/4P3/8/8/PPPP1PPP/RNBQKBNR b KQkq - 0 2";
+    let mut robochess = setup(fen);
+    undo(&mut robochess, &["d7d5", "e5d6"], 1);
+    assert_eq!(
+        robochess.position.to_fen(),
+        "rnbqkbnr/ppp1pppp/8/3pP3/8/8/PPPP1PPP/RNBQKBNR w KQkq d6 0 3"
+    );
+    assert_eq!(robochess.graveyard, Graveyard::new());
+    assert_eq!(robochess.history.len(), 1);
+}
+
+#[test]
+fn undo_substitute() {
+    let mut robochess = setup("8/4P3/8/8/8/8/k7/4K3 w - - 0 1");
+    robochess.promotion.policy = PromotionPolicy::Substitute;
+    robochess.graveyard.add(true, PieceType::ROOK);
+    undo(&mut robochess, &["e7e8q", "a2b2", "e8e5"], 2);
+    assert_eq!(robochess.substitutes, vec![(Square::E8, PieceType::ROOK)]);
+    assert_eq!(robochess.physical_board().unwrap()[60], Some('R'));
+    undo(&mut robochess, &[], 1);
+    assert!(robochess.substitutes.is_empty());
+    assert_eq!(robochess.graveyard.slots(true)[0], Some(PieceType::ROOK));
+}
+
+#[test]
+fn undo_too_many_moves() {
+    let mut robochess = setup(START);
+    robochess.make_move("e2e4").unwrap();
+    assert!(robochess.plan_undo(2).is_err());
+    assert!(robochess.plan_undo(1).is_ok());
+    robochess.set_position(Position::new());
+    assert!(robochess.plan_undo(1).is_err());
+}
+
+#[test]
+fn record_of_a_game() {
+    let mut robochess = setup(START);
+    for m in &["e2e4", "d7d5", "e4d5"] {
+        robochess.make_move(m).unwrap();
+    }
+    let record = robochess.record("Human", "Human");
+    assert_eq!(record.fen, START);
+    let moves: Vec<&str> = record.moves.iter().map(|(san, _)| san.as_str()).collect();
+    assert_eq!(moves, ["e4", "d5", "exd5"]);
+    assert_eq!(record.result, "*");
+}
+
+#[test]
+fn resume_from_journal() {
+    let mut robochess = setup("8/4P3/8/8/8/8/k7/4K3 w - - 0 1");
+    robochess.promotion.policy = PromotionPolicy::Substitute;
+    robochess.graveyard.add(true, PieceType::ROOK);
+    for m in &["e7e8q", "a2b2", "e8e5"] {
+        robochess.make_move(m).unwrap();
+    }
+    let journal = robochess.journal(None);
+    assert_eq!(journal.task(), None);
+
+    let mut resumed = setup(START);
+    resumed.restore(&journal).unwrap();
+    assert_eq!(resumed.position.to_fen(), robochess.position.to_fen());
+    assert_eq!(resumed.graveyard, robochess.graveyard);
+    assert_eq!(resumed.substitutes, vec![(Square::E5, PieceType::ROOK)]);
+    assert_eq!(
+        resumed.record("Human", "Human"),
+        robochess.record("Human", "Human")
+    );
+    // the moves can still be taken back
+    let commands = undo(&mut resumed, &[], 3);
+    assert!(!commands.is_empty());
+    assert_eq!(resumed.position.to_fen(), "8/4P3/8/8/8/8/k7/4K3 w - - 0 1");
+    assert_eq!(resumed.graveyard.slots(true)[0], Some(PieceType::ROOK));
+
+    let mut broken = journal;
+    broken.history[1].m = "h1h2".to_string();
+    assert!(resumed.restore(&broken).is_err());
+}
+
+/// Returns a game restored from a journal that was interrupted in the capture e4d5, with the
+/// captured pawn in the tray and the white pawn on its way.
+fn interrupted_capture() -> (RoboChess<SimulatedGantry>, Progress) {
+    let fen = "rnbqkbnr/ppp1pppp/8/3p4/4P3/8/PPPP1PPP/RNBQKBNR w KQkq d6 0 2";
+    let robochess = setup(fen);
+    let m = robochess
+        .find_move(ParsedMove::from_coordinate_notation("e4d5").unwrap())
+        .unwrap();
+    let progress = Progress {
+        m: "e4d5".to_string(),
+        carries: robochess.plan_move(m).carries,
+        done: 1,
+        phase: Some(Phase::Carry),
+        back: false,
+    };
+    let journal = robochess.journal(Some(Task::Move(progress.clone())));
+    let mut resumed = setup(START);
+    resumed.restore(&journal).unwrap();
+    assert_eq!(resumed.position.to_fen(), fen);
+    (resumed, progress)
+}
+
+#[test]
+fn roll_interrupted_move_forward() {
+    let (mut robochess, progress) = interrupted_capture();
+    assert_eq!(progress.carries.len(), 2);
+    assert_eq!(progress.settled(), (1, Some(1)));
+    let start = robochess.controller.commands().len();
+    assert_eq!(robochess.recover(Roll::Forward).unwrap(), Vec::new());
+    // the pawn has been put on d5 by hand, so nothing is left to carry
+    assert_eq!(robochess.controller.commands().len(), start);
+    assert_eq!(
+        robochess.position.to_fen(),
+        "rnbqkbnr/ppp1pppp/8/3P4/8/8/PPPP1PPP/RNBQKBNR b KQkq - 0 2"
+    );
+    assert_eq!(robochess.graveyard.slots(false)[0], Some(PieceType::PAWN));
+    assert_eq!(robochess.history.len(), 1);
+    assert_eq!(robochess.interrupted, None);
+    assert_eq!(robochess.journal(None).task(), None);
+}
+
+#[test]
+fn roll_interrupted_move_back() {
+    let (mut robochess, _) = interrupted_capture();
+    let fen = robochess.position.to_fen();
+    let start = robochess.controller.commands().len();
+    assert_eq!(robochess.recover(Roll::Back).unwrap(), Vec::new());
+    // the captured pawn is carried back from the tray to d5
+    let commands = &robochess.controller.commands()[start..];
+    let (x, y) = robochess.geometry.slot_coordinates(false, 0);
+    assert_eq!(commands[0], Command::MoveFast(x, y));
+    let (x, y) = robochess.geometry.get_square_coordinates(Square::D5);
+    assert!(commands.contains(&Command::MoveSlow(x, y)));
+    assert_eq!(commands.last(), Some(&Command::Down));
+    assert_eq!(robochess.position.to_fen(), fen);
+    assert_eq!(robochess.graveyard, Graveyard::new());
+    assert!(robochess.history.is_empty());
+}
+
+#[test]
+fn out_of_bounds() {
+    let mut robochess = setup(START);
+    let start = robochess.controller.commands().len();
+    let min_x = robochess.geometry.min_x;
+    assert!(matches!(
+        robochess.move_to_xy(min_x - 1, 0),
+        Err(RoboChessError::OutOfBounds { .. })
+    ));
+    // the gantry doesn't move at all
+    assert_eq!(robochess.controller.commands().len(), start);
+}
+
+#[test]
+fn magnet_is_lowered_if_the_gantry_fails() {
+    let mut robochess = setup(START);
+    // the knight is picked up and carried to the first waypoint
+    robochess.controller.fail_after(2);
+    let start = robochess.controller.commands().len();
+    assert!(matches!(
+        robochess.make_move("g1f3"),
+        Err(RoboChessError::Hardware(_))
+    ));
+    let commands = &robochess.controller.commands()[start..];
+    assert!(matches!(
+        commands,
+        [
+            Command::MoveFast(..),
+            Command::Up,
+            Command::MoveSlow(..),
+            Command::Down
+        ]
+    ));
+    assert_eq!(robochess.position.to_fen(), START);
+
+    // the same for a step of an arrangement
+    let mut robochess = setup(START);
+    robochess.controller.fail_after(2);
+    let mut arrangement = robochess.plan_arrangement(CASTLE_WHITE).unwrap();
+    assert!(robochess.arrange_step(&mut arrangement).is_err());
+    assert_eq!(robochess.controller.commands().last(), Some(&Command::Down));
+    assert_eq!(arrangement.done, 0);
+}
+
+#[test]
+fn failed_move_has_to_be_recovered() {
+    let fen = "rnbqkbnr/ppp1pppp/8/3p4/4P3/8/PPPP1PPP/RNBQKBNR w KQkq d6 0 2";
+    let mut robochess = setup(fen);
+    // the captured pawn reaches the tray, the white pawn gets stuck on its way
+    robochess.controller.fail_after(6);
+    assert!(robochess.make_move("e4d5").is_err());
+    assert_eq!(robochess.position.to_fen(), fen);
+    let progress = robochess.interrupted.clone().unwrap();
+    assert_eq!(progress.settled(), (1, Some(1)));
+    // the journal keeps the move, even if it is saved for something else
+    robochess.save_journal(None);
+    assert_eq!(
+        robochess.journal(None).task(),
+        Some(Task::Move(progress.clone()))
+    );
+
+    robochess.controller.fail_after(usize::MAX);
+    assert!(robochess.make_move("d2d4").is_err());
+    assert!(robochess.plan_undo(1).is_err());
+    assert!(robochess.plan_arrangement(START).is_err());
+    assert_eq!(robochess.position.to_fen(), fen);
+
+    // the pawn has been put back on e4 by hand
+    assert_eq!(robochess.recover(Roll::Back).unwrap(), Vec::new());
+    assert_eq!(robochess.interrupted, None);
+    assert_eq!(robochess.graveyard, Graveyard::new());
+    assert!(robochess.recover(Roll::Back).is_err());
+    robochess.make_move("e4d5").unwrap();
+    assert_eq!(robochess.history.len(), 1);
+}
@@ -0,0 +1,1159 @@
+//! The websocket server. Commands of all clients are translated into [`Command`]s and executed
+//! by [`execute`], regardless of the protocol they were sent with.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::Duration;
+
+use websocket::server::NoTlsAcceptor;
+use websocket::sync::{Server, Writer};
+use websocket::OwnedMessage;
+
+use chers::{BitMove, Color, ParsedMove, Position};
+
+use crate::arrange::Arrangement;
+use crate::calibration::Calibration;
+use crate::config::{self, Config, Engine, Geometry, Side};
+use crate::engine;
+use crate::error::RoboChessError;
+use crate::gantry::Gantry;
+use crate::journal::{Journal, Task};
+use crate::pgn::{self, Record};
+use crate::protocol::{
+    self, Command, Game, Message, Reply, Saved, Status, JSON_SUB_PROTOCOL, TEXT_SUB_PROTOCOL,
+};
+use crate::robochess::{parse_square, square_center, Notice, RoboChess};
+
+/// Returns whose turn it is or how the game ended.
+fn status(position: &Position) -> Status {
+    if position.is_checkmate() {
+        Status::Checkmate
+    } else if position.is_draw() {
+        Status::Draw
+    } else if position.side_to_move() == Color::WHITE {
+        Status::White
+    } else {
+        Status::Black
+    }
+}
+
+fn game_over(position: &Position) -> bool {
+    matches!(status(position), Status::Checkmate | Status::Draw)
+}
+
+fn game<G: Gantry>(controller: &RoboChess<G>) -> Game {
+    Game {
+        fen: controller.position.to_fen(),
+        status: status(&controller.position),
+        awaited_piece: controller.awaited_piece.map(|sq| sq.to_string()),
+        moving: controller
+            .interrupted
+            .as_ref()
+            .map(|progress| progress.m.clone()),
+    }
+}
+
+/// Everything the commands of the clients operate on.
+struct State<G: Gantry> {
+    controller: RoboChess<G>,
+    calibration: Option<Calibration>,
+    engine: Engine,
+    /// Increased whenever the game or the engine settings change, so that a search that was
+    /// started before can be recognized as outdated.
+    generation: u64,
+    /// Have to be sent to all clients.
+    notices: Vec<Notice>,
+    /// Failures of the engine's moves, the arrangement or the replay, which no client asked for,
+    /// so they are sent to all clients.
+    errors: Vec<RoboChessError>,
+    /// The pieces are being arranged for a new position, one step per [`Event::ArrangeStep`].
+    arrangement: Option<Arrangement>,
+    /// The game of a PGN that is replayed, cf. [`Command::Import`].
+    replay: Option<Replay>,
+    replay_settings: config::Replay,
+    /// Where the games are saved, cf. [`crate::config::Server::games_directory`].
+    games_directory: PathBuf,
+    /// The game before the restart, until it is resumed or discarded, cf. [`Command::Resume`].
+    saved: Option<Journal>,
+}
+
+/// A game of a PGN that is replayed on the board, one move per [`Event::ReplayMove`].
+struct Replay {
+    white: String,
+    black: String,
+    /// The complete FEN of the start position.
+    fen: String,
+    moves: Vec<BitMove>,
+    /// The number of moves that have been played.
+    played: usize,
+    /// Seconds between two moves.
+    pause: f32,
+    paused: bool,
+    /// Whether the game is set up again after the last move, cf. [`config::Replay::repeat`].
+    repeat: bool,
+}
+
+impl<G: Gantry> State<G> {
+    /// Returns whether the engine has to play the next move.
+    fn engine_to_move(&self) -> bool {
+        let position = &self.controller.position;
+        match self.engine.side {
+            Some(side) => {
+                side.color() == position.side_to_move()
+                    && matches!(status(position), Status::White | Status::Black)
+                    && self.controller.awaited_piece.is_none()
+                    && self.controller.interrupted.is_none()
+                    && self.arrangement.is_none()
+                    && self.replay.is_none()
+                    && self.saved.is_none()
+            }
+            None => false,
+        }
+    }
+
+    /// Returns whether moves of the replayed game are left or it is repeated.
+    fn replaying(&self) -> bool {
+        matches!(&self.replay, Some(replay) if replay.played < replay.moves.len() || replay.repeat)
+    }
+
+    /// Returns whether the replay can go on, i.e. play the next move or set up the game again.
+    fn replay_to_move(&self) -> bool {
+        self.replaying()
+            && self.controller.awaited_piece.is_none()
+            && self.controller.interrupted.is_none()
+            && self.arrangement.is_none()
+    }
+
+    /// Returns how many moves of the replayed game have been played, of how many and whether the
+    /// replay is paused.
+    fn replay_progress(&self) -> Option<(usize, usize, bool)> {
+        let replay = self.replay.as_ref()?;
+        Some((replay.played, replay.moves.len(), replay.paused))
+    }
+
+    /// Plays the next move of the replayed game or sets it up again if it is repeated. Returns
+    /// whether a move has been played. If the gantry fails the replay is paused.
+    fn advance_replay(&mut self) -> Result<bool, RoboChessError> {
+        let replay = match self.replay.as_mut() {
+            Some(replay) => replay,
+            None => return Ok(false),
+        };
+        let m = match replay.moves.get(replay.played) {
+            Some(&m) => m,
+            None => {
+                // the replay is repeated
+                match self.controller.plan_arrangement(&replay.fen) {
+                    Ok(arrangement) => {
+                        replay.played = 0;
+                        let _ = start_arrangement(self, arrangement);
+                    }
+                    Err(e) => {
+                        eprintln!("could not set up the replayed game again: {}", e);
+                        replay.repeat = false;
+                    }
+                }
+                return Ok(false);
+            }
+        };
+        let repeat = replay.repeat;
+        let notices = match self.controller.play(m) {
+            Ok(notices) => notices,
+            Err(e) => {
+                replay.paused = true;
+                return Err(e);
+            }
+        };
+        replay.played += 1;
+        if let Some(played) = self.controller.history.last() {
+            println!("Replayed {}", played.san);
+        }
+        self.notices.extend(notices);
+        self.generation += 1;
+        if game_over(&self.controller.position) && !repeat {
+            self.save_game();
+        }
+        Ok(true)
+    }
+
+    /// Returns the record of the current game with the names of the players.
+    fn record(&self) -> Record {
+        let engine = format!("RoboChess (depth {})", self.engine.depth);
+        let (white, black) = match (&self.replay, self.engine.side) {
+            (Some(replay), _) => (replay.white.clone(), replay.black.clone()),
+            (None, Some(Side::White)) => (engine, "Human".to_string()),
+            (None, Some(Side::Black)) => ("Human".to_string(), engine),
+            (None, None) => ("Human".to_string(), "Human".to_string()),
+        };
+        self.controller.record(&white, &black)
+    }
+
+    /// Saves the current game to [`State::games_directory`] unless no move has been made.
+    fn save_game(&self) {
+        let record = self.record();
+        if record.moves.is_empty() {
+            return;
+        }
+        let path = self.games_directory.join(record.file_name());
+        match fs::create_dir_all(&self.games_directory)
+            .and_then(|()| fs::write(&path, record.to_pgn()))
+        {
+            Ok(()) => println!("Saved the game to {}", path.display()),
+            Err(e) => eprintln!("could not save {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// Handles the calibration commands:
+///
+/// - `CalibrationStart` moves the magnet over the first square that has to be measured
+/// - `CalibrationJog` moves the magnet by the given number of steps
+/// - `CalibrationConfirm` records the current position for the current square and moves on to the
+///   next one
+/// - `CalibrationCancel` aborts the calibration
+///
+/// Except for jogging the reply contains the square the magnet has to be moved over next.
+fn calibration_command<G: Gantry>(
+    state: &mut State<G>,
+    command: Command,
+) -> Result<Reply, RoboChessError> {
+    let controller = &mut state.controller;
+    let calibration = &mut state.calibration;
+    let cal = match (command, calibration.as_mut()) {
+        (Command::CalibrationStart, _) => calibration.insert(Calibration::new()),
+        (Command::CalibrationCancel, _) => {
+            *calibration = None;
+            return Ok(Reply::None);
+        }
+        (Command::CalibrationJog { dx, dy }, Some(_)) => {
+            let (x, y) = controller.jog(dx, dy)?;
+            return Ok(Reply::Position { x, y });
+        }
+        (Command::CalibrationConfirm, Some(cal)) => {
+            let sq = cal.next_square().ok_or_else(|| {
+                RoboChessError::Protocol("the calibration is complete".to_string())
+            })?;
+            cal.confirm(square_center(sq), controller.controller.position());
+            cal
+        }
+        (_, None) => {
+            return Err(RoboChessError::Protocol(
+                "no calibration running".to_string(),
+            ))
+        }
+        (command, _) => {
+            return Err(RoboChessError::Protocol(format!(
+                "{:?} is not a calibration command",
+                command
+            )))
+        }
+    };
+
+    if let Some(sq) = cal.next_square() {
+        let (x, y) = controller.geometry.get_square_coordinates(sq);
+        controller.controller.move_to_xy_fast(x, y)?;
+        return Ok(Reply::Calibration {
+            next_square: Some(sq.to_string()),
+        });
+    }
+
+    let result = cal.finish();
+    *calibration = None;
+    let transform = result.map_err(|e| RoboChessError::Calibration(e.to_string()))?;
+    let g = &controller.geometry;
+    let calibrated = Geometry {
+        calibration: Some(transform),
+        ..g.clone()
+    };
+    if let Some((x, y)) = calibrated.unreachable_point() {
+        return Err(RoboChessError::Calibration(format!(
+            "the board would reach out of bounds to {} {}",
+            x, y
+        )));
+    }
+    let result = transform.save(&g.calibration_file).map_err(|e| {
+        RoboChessError::Calibration(format!(
+            "could not save {}: {}",
+            g.calibration_file.display(),
+            e
+        ))
+    });
+    controller.geometry.calibration = Some(transform);
+    result?;
+    Ok(Reply::Calibration { next_square: None })
+}
+
+/// Starts to carry out the arrangement one step per [`Event::ArrangeStep`].
+fn start_arrangement<G: Gantry>(
+    state: &mut State<G>,
+    arrangement: Arrangement,
+) -> Result<Reply, RoboChessError> {
+    let total = arrangement.steps.len();
+    state.arrangement = Some(arrangement);
+    state.generation += 1;
+    Ok(Reply::Progress { done: 0, total })
+}
+
+/// Executes a command of either protocol. `control` tells whether the client is in control.
+fn execute<G: Gantry>(
+    state: &mut State<G>,
+    command: Command,
+    control: bool,
+) -> Result<Reply, RoboChessError> {
+    if command.needs_control() && !control {
+        return Err(RoboChessError::Busy(
+            "another client is in control".to_string(),
+        ));
+    }
+    if command.needs_control() && state.arrangement.is_some() {
+        return Err(RoboChessError::Busy(
+            "the pieces are being arranged".to_string(),
+        ));
+    }
+    let engine_to_move = state.engine_to_move();
+    let replaying = state.replaying();
+    let controller = &mut state.controller;
+    match command {
+        Command::Hello { version } => {
+            if version != protocol::VERSION {
+                return Err(RoboChessError::Protocol(format!(
+                    "unsupported protocol version {}, the server speaks version {}",
+                    version,
+                    protocol::VERSION
+                )));
+            }
+            return Ok(Reply::Hello {
+                version,
+                control,
+                saved: state.saved.as_ref().map(|journal| {
+                    Box::new(Saved {
+                        fen: journal.fen.clone(),
+                        moves: journal.history.len(),
+                        interrupted: journal.task().map(|task| task.to_string()),
+                        moving: journal.moving.clone(),
+                    })
+                }),
+                game: game(controller),
+            });
+        }
+        Command::Move { m } => {
+            if let Some(sq) = controller.awaited_piece {
+                return Err(RoboChessError::Busy(format!(
+                    "waiting for the promoted piece to be put on {} and confirmed",
+                    sq
+                )));
+            }
+            if replaying {
+                return Err(RoboChessError::Busy("a game is being replayed".to_string()));
+            }
+            if state.saved.is_some() {
+                return Err(RoboChessError::Busy(
+                    "resume or discard the saved game first".to_string(),
+                ));
+            }
+            if engine_to_move {
+                return Err(RoboChessError::Busy("it's the engine's turn".to_string()));
+            }
+            match controller.make_move(&m) {
+                Ok(notices) => state.notices.extend(notices),
+                Err(RoboChessError::IllegalMove(m)) => {
+                    // a promotion without a piece doesn't match any legal move
+                    let promotion = ParsedMove::from_coordinate_notation(&format!("{}q", m))
+                        .ok()
+                        .and_then(|parsed| controller.find_move(parsed))
+                        .is_some();
+                    if promotion {
+                        return Err(RoboChessError::IllegalMove(format!(
+                            "{}, the promotion piece is missing, e.g. {}q",
+                            m, m
+                        )));
+                    }
+                    return Err(RoboChessError::IllegalMove(m));
+                }
+                Err(e) => return Err(e),
+            }
+            if game_over(&state.controller.position) {
+                state.save_game();
+            }
+        }
+        Command::Fen { fen, arrange: true } => {
+            let arrangement = controller.plan_arrangement(&fen)?;
+            state.save_game();
+            state.replay = None;
+            state.saved = None;
+            return start_arrangement(state, arrangement);
+        }
+        Command::Fen {
+            fen,
+            arrange: false,
+        } => {
+            controller.check_interrupted()?;
+            let position =
+                Position::from_fen(&fen).map_err(|_| RoboChessError::InvalidFen(fen.clone()))?;
+            state.save_game();
+            state.replay = None;
+            state.saved = None;
+            state.controller.set_position(position);
+        }
+        Command::Placed => {
+            if !controller.confirm_placement() {
+                return Err(RoboChessError::Protocol(
+                    "no promoted piece is awaited".to_string(),
+                ));
+            }
+        }
+        Command::Reset { fen } => {
+            let fen = fen.unwrap_or_else(|| Position::new().to_fen());
+            let arrangement = controller.plan_arrangement(&fen)?;
+            state.save_game();
+            state.replay = None;
+            state.saved = None;
+            return start_arrangement(state, arrangement);
+        }
+        Command::Undo { moves } => {
+            if replaying {
+                return Err(RoboChessError::Busy("a game is being replayed".to_string()));
+            }
+            let arrangement = controller.plan_undo(moves)?;
+            return start_arrangement(state, arrangement);
+        }
+        Command::Pgn => {
+            return Ok(Reply::Pgn {
+                pgn: state.record().to_pgn(),
+            })
+        }
+        Command::Import { pgn, pause } => {
+            let pause = pause.unwrap_or(state.replay_settings.pause);
+            if pause < 0.0 {
+                return Err(RoboChessError::Protocol(
+                    "the pause must not be negative".to_string(),
+                ));
+            }
+            let imported = pgn::parse(&pgn).map_err(RoboChessError::InvalidPgn)?;
+            let arrangement = controller.plan_arrangement(&imported.fen)?;
+            state.save_game();
+            state.saved = None;
+            state.replay = Some(Replay {
+                white: imported.white,
+                black: imported.black,
+                fen: imported.fen,
+                moves: imported.moves,
+                played: 0,
+                pause,
+                paused: false,
+                repeat: state.replay_settings.repeat,
+            });
+            return start_arrangement(state, arrangement);
+        }
+        Command::ReplayPause | Command::ReplayResume => {
+            let replay = match state.replay.as_mut() {
+                Some(replay) if replaying => replay,
+                _ => {
+                    return Err(RoboChessError::Protocol(
+                        "no game is being replayed".to_string(),
+                    ))
+                }
+            };
+            replay.paused = command == Command::ReplayPause;
+            return Ok(Reply::Replay {
+                played: replay.played,
+                total: replay.moves.len(),
+                paused: replay.paused,
+            });
+        }
+        Command::ReplayStep => {
+            if !matches!(&state.replay, Some(replay) if replay.paused) {
+                return Err(RoboChessError::Protocol("no replay is paused".to_string()));
+            }
+            if !state.replay_to_move() {
+                return Err(RoboChessError::Busy(
+                    "the replay can't go on yet".to_string(),
+                ));
+            }
+            if !state.advance_replay()? {
+                // the game is set up again
+                return Ok(Reply::None);
+            }
+        }
+        Command::Resume { roll } => {
+            // a saved game may itself have been interrupted in a move
+            let task = match &state.saved {
+                Some(journal) => journal.task(),
+                None => match &controller.interrupted {
+                    Some(progress) => Some(Task::Move(progress.clone())),
+                    None => {
+                        return Err(RoboChessError::Protocol(
+                            "there is no saved game or interrupted move".to_string(),
+                        ))
+                    }
+                },
+            };
+            match (&task, roll) {
+                (Some(Task::Move(progress)), None) => {
+                    return Err(RoboChessError::Protocol(format!(
+                        "the robot was interrupted while {}, roll the move forward or back",
+                        progress
+                    )))
+                }
+                (Some(Task::Move(_)), Some(_)) | (_, None) => {}
+                (_, Some(_)) => {
+                    return Err(RoboChessError::Protocol(
+                        "the robot wasn't interrupted in a move".to_string(),
+                    ))
+                }
+            }
+            if let Some(journal) = state.saved.take() {
+                controller
+                    .restore(&journal)
+                    .map_err(RoboChessError::Journal)?;
+                println!("Resumed the game after {} moves", journal.history.len());
+            }
+            match (task, roll) {
+                (Some(Task::Move(_)), Some(roll)) => {
+                    let notices = controller.recover(roll)?;
+                    state.notices.extend(notices);
+                }
+                (Some(task), _) => state.notices.push(Notice::Interrupted { task }),
+                (None, _) => {}
+            }
+        }
+        Command::Discard => {
+            if state.saved.take().is_none() {
+                return Err(RoboChessError::Protocol(
+                    "there is no saved game".to_string(),
+                ));
+            }
+            controller.save_journal(None);
+            return Ok(Reply::None);
+        }
+        Command::State => {}
+        Command::Home => {
+            controller.controller.init_sequence()?;
+            return Ok(Reply::None);
+        }
+        Command::Engine { side, depth, time } => {
+            let engine = Engine { side, depth, time };
+            engine.validate().map_err(RoboChessError::Protocol)?;
+            state.engine = engine;
+            state.generation += 1;
+            return Ok(Reply::Engine(engine));
+        }
+        Command::CalibrationStart
+        | Command::CalibrationJog { .. }
+        | Command::CalibrationConfirm
+        | Command::CalibrationCancel => {
+            return calibration_command(state, command);
+        }
+        Command::Jog { dx, dy } => {
+            let (x, y) = controller.jog(dx, dy)?;
+            return Ok(Reply::Position { x, y });
+        }
+        Command::MoveTo { x, y } => {
+            controller.move_to_xy(x, y)?;
+            return Ok(Reply::Position { x, y });
+        }
+        Command::MoveToSquare { square } => {
+            let sq = parse_square(&square)
+                .ok_or_else(|| RoboChessError::Protocol(format!("invalid square {}", square)))?;
+            let (x, y) = controller.geometry.board_to_xy(square_center(sq));
+            controller.move_to_xy(x, y)?;
+            return Ok(Reply::Position { x, y });
+        }
+        Command::Up | Command::Down | Command::Position => {
+            match command {
+                Command::Up => controller.controller.up(),
+                Command::Down => controller.controller.down(),
+                _ => {}
+            }
+            let (x, y) = controller.controller.position();
+            return Ok(Reply::Position { x, y });
+        }
+    }
+    state.generation += 1;
+    Ok(Reply::Game(game(&state.controller)))
+}
+
+/// Formats the result of a command the way the text protocol expects it:
+///
+/// - moves and `!placed` are answered with whose turn it is (`!white`, `!black`, `!checkmate` or
+///   `!draw`). If the position didn't change as requested, `!set <fen>` is sent first to reset
+///   the board of the client, preceded by `!error <reason>` unless the move was illegal.
+/// - `!fen` and `!reset` are answered with `!progress 0 <total>`, the new position follows once
+///   the pieces are arranged. If that's not possible, `!error <reason>` is followed by the
+///   current position. `!undo` is answered the same way, but without the position on errors.
+/// - `!calibration` is answered with `!calibration <square>`, `!calibration position <x> <y>`,
+///   `!calibration done`, `!calibration cancelled` or `!calibration error <reason>`.
+/// - the manual commands are answered with `!position <x> <y>` or `!error <reason>`.
+/// - `!engine` and `!calibrate` are only answered if they fail, with `!error <reason>`.
+fn text_reply<G: Gantry>(
+    controller: &RoboChess<G>,
+    command: &Command,
+    result: Result<Reply, RoboChessError>,
+) -> Vec<String> {
+    let status = match status(&controller.position) {
+        Status::White => "!white",
+        Status::Black => "!black",
+        Status::Checkmate => "!checkmate",
+        Status::Draw => "!draw",
+    };
+    let set = format!("!set {}", controller.position.to_fen());
+    let calibration = matches!(
+        command,
+        Command::CalibrationStart
+            | Command::CalibrationJog { .. }
+            | Command::CalibrationConfirm
+            | Command::CalibrationCancel
+    );
+    let reply = match (command, result) {
+        (Command::Move { .. }, Ok(_))
+        | (Command::Placed, Ok(_))
+        | (Command::ReplayStep, Ok(Reply::Game(_))) => return vec![status.to_string()],
+        (Command::Fen { .. }, Ok(Reply::Progress { done, total })) => {
+            format!("!progress {} {}", done, total)
+        }
+        (Command::Move { .. }, Err(RoboChessError::IllegalMove(_)))
+        | (Command::Fen { .. }, Ok(_))
+        | (Command::Resume { .. }, Ok(_)) => return vec![set, status.to_string()],
+        (Command::Move { .. }, Err(e)) | (Command::Fen { .. }, Err(e)) => {
+            return vec![format!("!error {}", e), set, status.to_string()]
+        }
+        (Command::Home, Ok(_)) => return Vec::new(),
+        (Command::CalibrationCancel, Ok(_)) => "!calibration cancelled".to_string(),
+        (_, Ok(Reply::Calibration { next_square })) => match next_square {
+            Some(sq) => format!("!calibration {}", sq),
+            None => "!calibration done".to_string(),
+        },
+        (_, Ok(Reply::Position { x, y })) if calibration => {
+            format!("!calibration position {} {}", x, y)
+        }
+        (_, Err(e)) if calibration => format!("!calibration error {}", e),
+        (_, Ok(Reply::Position { x, y })) => format!("!position {} {}", x, y),
+        (_, Ok(Reply::Progress { done, total })) => format!("!progress {} {}", done, total),
+        (_, Ok(Reply::Pgn { pgn })) => format!("!pgn {}", pgn),
+        // sent to all clients anyway
+        (_, Ok(Reply::Replay { .. })) => return Vec::new(),
+        (_, Ok(_)) => return Vec::new(),
+        (_, Err(e)) => format!("!error {}", e),
+    };
+    vec![reply]
+}
+
+/// The protocol a client has chosen, cf. [`protocol`].
+enum Session {
+    Text,
+    Json { greeted: bool },
+}
+
+impl Session {
+    /// Handles a text message of the client and returns the replies and whether the game has
+    /// changed.
+    fn handle<G: Gantry>(
+        &mut self,
+        state: &mut State<G>,
+        data: &str,
+        control: bool,
+    ) -> (Vec<String>, bool) {
+        match self {
+            Session::Text => match Command::from_text(data) {
+                Some(command) => {
+                    let result = execute(state, command.clone(), control);
+                    let changed = changes_game(&command) && result.is_ok();
+                    (text_reply(&state.controller, &command, result), changed)
+                }
+                None => {
+                    println!("Invalid command {}", data);
+                    (Vec::new(), false)
+                }
+            },
+            Session::Json { greeted } => {
+                let request = match protocol::parse_request(data) {
+                    Ok(request) => request,
+                    Err(message) => return (vec![message.to_json()], false),
+                };
+                let id = request.id;
+                let hello = matches!(request.command, Command::Hello { .. });
+                let changed = changes_game(&request.command);
+                let result = if *greeted || hello {
+                    let result = execute(state, request.command, control);
+                    *greeted |= hello && result.is_ok();
+                    result
+                } else {
+                    Err(RoboChessError::Protocol(
+                        "the first request has to be hello".to_string(),
+                    ))
+                };
+                let changed = changed && result.is_ok();
+                let message = match result {
+                    Ok(result) => Message::Ok { id, result },
+                    Err(e) => Message::Error {
+                        id: Some(id),
+                        kind: e.kind(),
+                        reason: e.to_string(),
+                    },
+                };
+                (vec![message.to_json()], changed)
+            }
+        }
+    }
+
+    /// Returns the messages that inform the client about a change of the game by another client.
+    fn state<G: Gantry>(&self, controller: &RoboChess<G>) -> Vec<String> {
+        match self {
+            Session::Text => {
+                let set = Command::Fen {
+                    fen: controller.position.to_fen(),
+                    arrange: false,
+                };
+                text_reply(controller, &set, Ok(Reply::None))
+            }
+            Session::Json { greeted: true } => vec![Message::State {
+                game: game(controller),
+            }
+            .to_json()],
+            Session::Json { greeted: false } => Vec::new(),
+        }
+    }
+
+    /// Returns the messages that tell the client about something the robot couldn't do on its own.
+    fn notice(&self, notice: &Notice) -> Vec<String> {
+        match self {
+            Session::Text => vec![format!("!notice {}", notice)],
+            Session::Json { greeted: true } => vec![Message::Notice {
+                text: notice.to_string(),
+            }
+            .to_json()],
+            Session::Json { greeted: false } => Vec::new(),
+        }
+    }
+
+    /// Returns the messages that tell the client about a failure no client asked for.
+    fn error(&self, e: &RoboChessError) -> Vec<String> {
+        match self {
+            Session::Text => vec![format!("!error {}", e)],
+            Session::Json { greeted: true } => vec![Message::Error {
+                id: None,
+                kind: e.kind(),
+                reason: e.to_string(),
+            }
+            .to_json()],
+            Session::Json { greeted: false } => Vec::new(),
+        }
+    }
+
+    /// Returns the messages that tell the client how far the arrangement of the pieces is.
+    fn progress(&self, done: usize, total: usize) -> Vec<String> {
+        match self {
+            Session::Text => vec![format!("!progress {} {}", done, total)],
+            Session::Json { greeted: true } => vec![Message::Progress { done, total }.to_json()],
+            Session::Json { greeted: false } => Vec::new(),
+        }
+    }
+
+    /// Returns the messages that tell the client how far the replay is.
+    fn replay(&self, played: usize, total: usize, paused: bool) -> Vec<String> {
+        match self {
+            Session::Text if paused => vec![format!("!replay {} {} paused", played, total)],
+            Session::Text => vec![format!("!replay {} {}", played, total)],
+            Session::Json { greeted: true } => vec![Message::Replay {
+                played,
+                total,
+                paused,
+            }
+            .to_json()],
+            Session::Json { greeted: false } => Vec::new(),
+        }
+    }
+
+    /// Returns the messages that inform the client that it got control.
+    fn control(&self) -> Vec<String> {
+        match self {
+            Session::Json { greeted: true } => vec![Message::Control.to_json()],
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Returns whether the command changes the game if it succeeds.
+fn changes_game(command: &Command) -> bool {
+    matches!(
+        command,
+        Command::Move { .. }
+            | Command::Fen { arrange: false, .. }
+            | Command::Placed
+            | Command::ReplayStep
+            | Command::Resume { .. }
+    )
+}
+
+/// Something that happened on one of the connections.
+enum Event {
+    Connected(usize, Client),
+    Message(usize, OwnedMessage),
+    Disconnected(usize),
+    /// The engine has found a move in the game with the given generation.
+    EngineMove(u64, Option<String>),
+    /// The next piece of [`State::arrangement`] can be carried.
+    ArrangeStep,
+    /// The pause before the next move of [`State::replay`] in the game with the given generation
+    /// is over.
+    ReplayMove(u64),
+}
+
+struct Client {
+    ip: SocketAddr,
+    sender: Writer<TcpStream>,
+    session: Session,
+}
+
+impl Client {
+    /// Sends the messages to the client and returns false if the connection is broken.
+    fn send(&mut self, messages: Vec<String>) -> bool {
+        messages.into_iter().all(|message| {
+            self.sender
+                .send_message(&OwnedMessage::Text(message))
+                .is_ok()
+        })
+    }
+}
+
+/// Accepts new connections and starts a thread for every client, which forwards the messages of the
+/// client as [`Event`]s.
+fn accept(server: Server<NoTlsAcceptor>, events: Sender<Event>) {
+    let mut next_id = 0;
+    for request in server.filter_map(Result::ok) {
+        let protocols = request.protocols();
+        let (session, sub_protocol) = if protocols.contains(&JSON_SUB_PROTOCOL.to_string()) {
+            (Session::Json { greeted: false }, JSON_SUB_PROTOCOL)
+        } else if protocols.contains(&TEXT_SUB_PROTOCOL.to_string()) {
+            (Session::Text, TEXT_SUB_PROTOCOL)
+        } else {
+            println!("Invalid subprotocols: {:?}", protocols);
+            let _ = request.reject();
+            continue;
+        };
+
+        let client = match request.use_protocol(sub_protocol).accept() {
+            Ok(client) => client,
+            Err(_) => continue,
+        };
+        let ip = match client.peer_addr() {
+            Ok(ip) => ip,
+            Err(_) => continue,
+        };
+        let (mut receiver, sender) = match client.split() {
+            Ok(split) => split,
+            Err(_) => continue,
+        };
+
+        let id = next_id;
+        next_id += 1;
+        let client = Client {
+            ip,
+            sender,
+            session,
+        };
+        if events.send(Event::Connected(id, client)).is_err() {
+            return;
+        }
+        let events = events.clone();
+        thread::spawn(move || {
+            for message in receiver.incoming_messages() {
+                let message = match message {
+                    Ok(message) => message,
+                    Err(_) => break,
+                };
+                let close = matches!(message, OwnedMessage::Close(_));
+                if events.send(Event::Message(id, message)).is_err() || close {
+                    return;
+                }
+            }
+            let _ = events.send(Event::Disconnected(id));
+        });
+    }
+}
+
+/// Forgets a client whose connection is broken or closed.
+fn disconnect(clients: &mut BTreeMap<usize, Client>, id: usize) {
+    if let Some(client) = clients.remove(&id) {
+        println!("Client {} disconnected", client.ip);
+        let _ = client.sender.shutdown_all();
+    }
+}
+
+/// Serves all clients. The connections are accepted and read on other threads, but the commands
+/// are executed one after another on this thread, which owns the robot. A command that fails is
+/// answered with the error and the server goes on serving. Fails only if the server can't be
+/// started.
+///
+/// `saved` is the game before the restart, which is offered to the clients to be resumed.
+pub fn serve<G: Gantry>(
+    controller: RoboChess<G>,
+    config: &Config,
+    saved: Option<Journal>,
+) -> Result<(), RoboChessError> {
+    let server = Server::bind((config.server.address.as_str(), config.server.port))
+        .map_err(RoboChessError::Io)?;
+    let (events, receiver) = mpsc::channel();
+    let accept_events = events.clone();
+    thread::spawn(move || accept(server, accept_events));
+
+    let mut state = State {
+        controller,
+        calibration: None,
+        engine: config.engine,
+        generation: 0,
+        notices: Vec::new(),
+        errors: Vec::new(),
+        arrangement: None,
+        replay: None,
+        replay_settings: config.replay,
+        games_directory: config.server.games_directory.clone(),
+        saved,
+    };
+    // the generation of the game the engine is searching a move for
+    let mut searching = None;
+    // whether an `Event::ArrangeStep` has been sent and not been handled yet
+    let mut arranging = false;
+    // whether an `Event::ReplayMove` will be sent and has not been handled yet
+    let mut replaying = false;
+    // the progress of the replay the clients have been told about
+    let mut replay_progress = None;
+    // ordered by the time of connection, so that control is handed on to the longest connected
+    let mut clients: BTreeMap<usize, Client> = BTreeMap::new();
+    let mut control = None;
+
+    for event in receiver {
+        // the clients whose connection broke while handling the event
+        let mut disconnected = BTreeSet::new();
+        match event {
+            Event::Connected(id, mut client) => {
+                println!("Connection from {}", client.ip);
+                if let Session::Text = client.session {
+                    let mut messages = vec![format!("!set {}", state.controller.position.to_fen())];
+                    if let Some(journal) = &state.saved {
+                        messages.push(format!("!saved {} {}", journal.history.len(), journal.fen));
+                        if let Some(task) = journal.task() {
+                            messages
+                                .push(format!("!notice the robot was interrupted while {}", task));
+                        }
+                    } else if let Some(progress) = &state.controller.interrupted {
+                        messages.push(format!(
+                            "!notice the robot was interrupted while {}",
+                            progress
+                        ));
+                    }
+                    if !client.send(messages) {
+                        continue;
+                    }
+                }
+                clients.insert(id, client);
+                if control.is_none() {
+                    control = Some(id);
+                }
+            }
+            Event::Message(id, OwnedMessage::Text(data)) => {
+                let client = match clients.get_mut(&id) {
+                    Some(client) => client,
+                    None => continue,
+                };
+                let (replies, changed) =
+                    client
+                        .session
+                        .handle(&mut state, &data, control == Some(id));
+                if !client.send(replies) {
+                    disconnected.insert(id);
+                }
+                if changed {
+                    for (&other, client) in clients.iter_mut().filter(|(&other, _)| other != id) {
+                        if !client.send(client.session.state(&state.controller)) {
+                            disconnected.insert(other);
+                        }
+                    }
+                }
+            }
+            Event::Message(id, OwnedMessage::Ping(ping)) => {
+                if let Some(client) = clients.get_mut(&id) {
+                    if client
+                        .sender
+                        .send_message(&OwnedMessage::Pong(ping))
+                        .is_err()
+                    {
+                        disconnected.insert(id);
+                    }
+                }
+            }
+            Event::Message(id, OwnedMessage::Close(_)) => {
+                if let Some(client) = clients.get_mut(&id) {
+                    let _ = client.sender.send_message(&OwnedMessage::Close(None));
+                }
+                disconnected.insert(id);
+            }
+            Event::Message(_, message) => println!("Unexpected message: {:?}", message),
+            Event::Disconnected(id) => {
+                disconnected.insert(id);
+            }
+            Event::EngineMove(generation, m) => {
+                // a search for the current game may still be running when an old result comes in
+                if searching == Some(generation) {
+                    searching = None;
+                }
+                if generation != state.generation {
+                    // the game has changed in the meantime
+                    continue;
+                }
+                match m.as_deref().map(|m| (m, state.controller.make_move(m))) {
+                    Some((m, Ok(notices))) => {
+                        println!("Engine played {}", m);
+                        state.notices.extend(notices);
+                        state.generation += 1;
+                        for (&id, client) in clients.iter_mut() {
+                            if !client.send(client.session.state(&state.controller)) {
+                                disconnected.insert(id);
+                            }
+                        }
+                        if game_over(&state.controller.position) {
+                            state.save_game();
+                        }
+                    }
+                    Some((m, Err(e))) => {
+                        eprintln!("Could not play the engine's move {}: {}", m, e);
+                        // the engine doesn't search again until the game changes
+                        searching = Some(state.generation);
+                        state.errors.push(e);
+                    }
+                    None => eprintln!("The engine found no legal move"),
+                }
+            }
+            Event::ArrangeStep => {
+                arranging = false;
+                let arrangement = match &mut state.arrangement {
+                    Some(arrangement) => arrangement,
+                    None => continue,
+                };
+                match state.controller.arrange_step(arrangement) {
+                    Ok(complete) => {
+                        let (done, total) = (arrangement.done, arrangement.steps.len());
+                        for (&id, client) in clients.iter_mut() {
+                            let mut messages = client.session.progress(done, total);
+                            if complete {
+                                messages.extend(client.session.state(&state.controller));
+                            }
+                            if !client.send(messages) {
+                                disconnected.insert(id);
+                            }
+                        }
+                        if complete {
+                            println!("Arranged {}", arrangement.fen);
+                            state.arrangement = None;
+                            state.generation += 1;
+                        }
+                    }
+                    Err(e) => {
+                        // the journal still names the arrangement, so a restart reports it
+                        eprintln!("Could not arrange {}: {}", arrangement.fen, e);
+                        state.arrangement = None;
+                        state.generation += 1;
+                        state.errors.push(e);
+                    }
+                }
+            }
+            Event::ReplayMove(generation) => {
+                replaying = false;
+                let paused = matches!(&state.replay, Some(replay) if replay.paused);
+                if generation == state.generation && !paused && state.replay_to_move() {
+                    match state.advance_replay() {
+                        Ok(true) => {
+                            for (&id, client) in clients.iter_mut() {
+                                if !client.send(client.session.state(&state.controller)) {
+                                    disconnected.insert(id);
+                                }
+                            }
+                        }
+                        Ok(false) => {}
+                        Err(e) => {
+                            eprintln!("Could not replay the next move: {}", e);
+                            state.errors.push(e);
+                        }
+                    }
+                }
+            }
+        }
+
+        for notice in state.notices.drain(..) {
+            println!("Notice: {}", notice);
+            for (&id, client) in clients.iter_mut() {
+                if !client.send(client.session.notice(&notice)) {
+                    disconnected.insert(id);
+                }
+            }
+        }
+
+        for e in state.errors.drain(..) {
+            for (&id, client) in clients.iter_mut() {
+                if !client.send(client.session.error(&e)) {
+                    disconnected.insert(id);
+                }
+            }
+        }
+
+        // carry one piece at a time, so that the clients are answered in between
+        if state.arrangement.is_some() && !arranging {
+            arranging = true;
+            let _ = events.send(Event::ArrangeStep);
+        }
+
+        if state.replay_progress() != replay_progress {
+            replay_progress = state.replay_progress();
+            if let Some((played, total, paused)) = replay_progress {
+                for (&id, client) in clients.iter_mut() {
+                    if !client.send(client.session.replay(played, total, paused)) {
+                        disconnected.insert(id);
+                    }
+                }
+            }
+        }
+
+        // the moves of a replayed game are played like the ones of the engine, after a pause
+        if let Some(replay) = &state.replay {
+            if state.replay_to_move() && !replay.paused && !replaying {
+                replaying = true;
+                let generation = state.generation;
+                let pause = Duration::from_secs_f32(replay.pause);
+                let events = events.clone();
+                thread::spawn(move || {
+                    thread::sleep(pause);
+                    let _ = events.send(Event::ReplayMove(generation));
+                });
+            }
+        }
+
+        if state.engine_to_move() && searching != Some(state.generation) {
+            searching = Some(state.generation);
+            let generation = state.generation;
+            let events = events.clone();
+            engine::start(state.controller.position.to_fen(), state.engine, move |m| {
+                let _ = events.send(Event::EngineMove(generation, m));
+            });
+        }
+
+        for id in disconnected {
+            disconnect(&mut clients, id);
+        }
+        // hand control on to the longest connected client that can still be reached
+        while matches!(control, Some(id) if !clients.contains_key(&id)) {
+            control = None;
+            if let Some((&id, client)) = clients.iter_mut().next() {
+                control = Some(id);
+                let messages = client.session.control();
+                if !client.send(messages) {
+                    // the next one is tried
+                    disconnect(&mut clients, id);
+                }
+            }
+        }
+    }
+    Ok(())
+}
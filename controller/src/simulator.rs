@@ -0,0 +1,114 @@
+use crate::config::Geometry;
+use crate::error::RoboChessError;
+use crate::gantry::Gantry;
+
+/// A single command that has been sent to a [`SimulatedGantry`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Home,
+    MoveSlow(i32, i32),
+    MoveFast(i32, i32),
+    Up,
+    Down,
+}
+
+/// In-memory replacement for [`crate::hbot::HBot`]. Instead of turning any motors it records every
+/// command, so that the motion of the robot can be inspected without the hardware.
+pub struct SimulatedGantry {
+    commands: Vec<Command>,
+    geometry: Geometry,
+    verbose: bool,
+    x: i32,
+    y: i32,
+    /// The number of moves that succeed before every further move fails, cf.
+    /// [`SimulatedGantry::fail_after`].
+    moves_left: Option<usize>,
+}
+
+impl SimulatedGantry {
+    /// Creates a new simulated gantry with the bounds from `geometry`. If `verbose` is set every
+    /// command is also printed.
+    pub fn new(geometry: &Geometry, verbose: bool) -> Self {
+        let mut res = Self {
+            commands: Vec::new(),
+            geometry: geometry.clone(),
+            verbose,
+            x: 0,
+            y: 0,
+            moves_left: None,
+        };
+        res.record(Command::Home);
+        res
+    }
+
+    /// Returns all the commands recorded so far.
+    #[cfg(test)]
+    pub fn commands(&self) -> &[Command] {
+        &self.commands
+    }
+
+    /// Lets the given number of moves succeed and every further move fail with
+    /// [`RoboChessError::Hardware`], like a gantry that gets stuck.
+    #[cfg(test)]
+    pub fn fail_after(&mut self, moves: usize) {
+        self.moves_left = Some(moves);
+    }
+
+    fn record(&mut self, command: Command) {
+        if self.verbose {
+            println!("{:?}", command);
+        }
+        self.commands.push(command);
+    }
+
+    fn move_to_xy(&mut self, x: i32, y: i32) -> Result<(), RoboChessError> {
+        let g = &self.geometry;
+        if !(g.min_x..=g.max_x).contains(&x) || !(g.min_y..=g.max_y).contains(&y) {
+            return Err(RoboChessError::OutOfBounds { x, y });
+        }
+        if let Some(moves) = &mut self.moves_left {
+            if *moves == 0 {
+                return Err(RoboChessError::Hardware("the gantry is stuck".to_string()));
+            }
+            *moves -= 1;
+        }
+        self.x = x;
+        self.y = y;
+        Ok(())
+    }
+}
+
+impl Gantry for SimulatedGantry {
+    fn init_sequence(&mut self) -> Result<(), RoboChessError> {
+        self.record(Command::Home);
+        self.x = 0;
+        self.y = 0;
+        Ok(())
+    }
+
+    fn move_to_xy_slow(&mut self, x: i32, y: i32) -> Result<(), RoboChessError> {
+        self.move_to_xy(x, y)?;
+        self.record(Command::MoveSlow(x, y));
+        Ok(())
+    }
+
+    fn move_to_xy_fast(&mut self, x: i32, y: i32) -> Result<(), RoboChessError> {
+        self.move_to_xy(x, y)?;
+        self.record(Command::MoveFast(x, y));
+        Ok(())
+    }
+
+    fn up(&mut self) {
+        self.record(Command::Up);
+    }
+
+    fn down(&mut self) {
+        self.record(Command::Down);
+    }
+
+    fn position(&self) -> (i32, i32) {
+        (self.x, self.y)
+    }
+
+    fn wait(&self) {}
+}
@@ -1,7 +1,50 @@
+use std::thread;
+use std::time::Duration;
+
 use rust_gpiozero::DigitalOutputDevice;
+use serde::Deserialize;
 
 const INF: i32 = 1_000_000_000;
 
+/// How long the step pin is held high for a single step.
+const PULSE: Duration = Duration::from_micros(10);
+
+/// Limits for the motion of a stepper motor. All values are measured in steps per second
+/// (squared, cubed).
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub max_speed: f32,
+    pub acceleration: f32,
+    pub jerk: f32,
+}
+
+impl Profile {
+    /// The maximal speed that can be reached `s` steps after standing still. The beginning of the
+    /// ramp is limited by the jerk and the rest by the acceleration. This is a bit more
+    /// conservative than a real s-curve, but it is cheap enough to compute on the pi.
+    fn speed_limit(&self, s: f32) -> f32 {
+        let accelerated = (2.0 * self.acceleration * s).sqrt();
+        let jerk_limited = self.jerk / 2.0 * (6.0 * s / self.jerk).powf(2.0 / 3.0);
+        self.max_speed.min(accelerated).min(jerk_limited)
+    }
+
+    /// Returns the point in time of each step of a move with the given number of steps, measured
+    /// in seconds from the beginning of the move. The speed ramps up at the beginning and down at
+    /// the end of the move, so the motor starts and stops without losing steps.
+    pub fn step_times(&self, steps: u32) -> Vec<f32> {
+        let mut t = 0.0;
+        (0..steps)
+            .map(|i| {
+                let s = i as f32 + 0.5;
+                let v = self.speed_limit(s).min(self.speed_limit(steps as f32 - s));
+                t += 1.0 / v;
+                t
+            })
+            .collect()
+    }
+}
+
 /// Low level control for a stepper motor. This struct has no context and does not know how many
 /// steps the motor has already turned.
 pub struct StepperMotor {
@@ -52,8 +95,51 @@ impl StepperMotor {
         self.step.blink(delay / 2.0, delay / 2.0);
     }
 
+    /// Set the direction for [`step`]. The actual direction also depends on the wiring.
+    pub fn set_direction(&mut self, dir: bool) {
+        if dir {
+            self.dir.on();
+        } else {
+            self.dir.off();
+        }
+    }
+
     /// Block until the given amount of steps have passed.
     pub fn wait(&mut self) {
         self.step.wait();
     }
 }
+
+/// Make a single step with all the given motors at the same time.
+pub fn step(motors: &mut [&mut StepperMotor]) {
+    for m in motors.iter_mut() {
+        m.step.on();
+    }
+    thread::sleep(PULSE);
+    for m in motors.iter_mut() {
+        m.step.off();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trapezoidal_ramp() {
+        let profile = Profile {
+            max_speed: 400.0,
+            acceleration: 1500.0,
+            jerk: 15000.0,
+        };
+        let times = profile.step_times(1000);
+        let delays: Vec<f32> = times.windows(2).map(|w| w[1] - w[0]).collect();
+
+        // never faster than the maximal speed, which is reached in the middle of the move
+        assert!(delays.iter().all(|&d| d >= 1.0 / 400.0 - 1e-6));
+        assert!((delays[500] - 1.0 / 400.0).abs() < 1e-6);
+        // slow at both ends
+        assert!(delays[0] > 4.0 * delays[500]);
+        assert!(delays[delays.len() - 1] > 4.0 * delays[500]);
+    }
+}
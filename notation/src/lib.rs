@@ -0,0 +1,209 @@
+//! Chess notation that `chers` doesn't provide, shared by the controller and the web interface: the
+//! pieces of a FEN and moves in standard algebraic notation, which needs to know whether a move
+//! gives check.
+
+use chers::{BitMove, Color, PieceType, Position, Square};
+
+/// The pieces on the board by square index `8 * rank + file`, as letters like in a FEN: upper case
+/// for white and lower case for black.
+pub type Board = [Option<char>; 64];
+
+const KNIGHT_OFFSETS: [(i32, i32); 8] = [
+    (1, 2),
+    (2, 1),
+    (2, -1),
+    (1, -2),
+    (-1, -2),
+    (-2, -1),
+    (-2, 1),
+    (-1, 2),
+];
+const ROOK_DIRECTIONS: [(i32, i32); 4] = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+const BISHOP_DIRECTIONS: [(i32, i32); 4] = [(1, 1), (-1, 1), (-1, -1), (1, -1)];
+
+/// Parses the pieces of a FEN. Returns `None` if they don't describe eight ranks of eight squares.
+pub fn parse_board(fen: &str) -> Option<Board> {
+    let mut board = [None; 64];
+    let rows: Vec<&str> = fen.split(' ').next()?.split('/').collect();
+    if rows.len() != 8 {
+        return None;
+    }
+    for (i, row) in rows.iter().enumerate() {
+        let rank = 7 - i;
+        let mut file = 0;
+        for c in row.chars() {
+            if let Some(n) = c.to_digit(10) {
+                file += n as usize;
+            } else {
+                *board.get_mut(8 * rank + file).filter(|_| file < 8)? = Some(c);
+                file += 1;
+            }
+        }
+        if file != 8 {
+            return None;
+        }
+    }
+    Some(board)
+}
+
+/// Returns the piece at the given file and rank or `None` if it is empty or off the board.
+fn at(board: &Board, file: i32, rank: i32) -> Option<char> {
+    if (0..8).contains(&file) && (0..8).contains(&rank) {
+        board[(8 * rank + file) as usize]
+    } else {
+        None
+    }
+}
+
+/// Returns whether the king of the given colour is attacked.
+fn in_check(board: &Board, white: bool) -> bool {
+    let king = if white { 'K' } else { 'k' };
+    let i = match board.iter().position(|&p| p == Some(king)) {
+        Some(i) => i as i32,
+        None => return false,
+    };
+    let (file, rank) = (i % 8, i / 8);
+    // the pieces of the opponent in the letters of the white pieces
+    let enemy = |f: i32, r: i32| {
+        at(board, f, r)
+            .filter(|c| c.is_ascii_uppercase() != white)
+            .map(|c| c.to_ascii_uppercase())
+    };
+    let forward = if white { 1 } else { -1 };
+    if [-1, 1]
+        .iter()
+        .any(|df| enemy(file + df, rank + forward) == Some('P'))
+    {
+        return true;
+    }
+    if KNIGHT_OFFSETS
+        .iter()
+        .any(|(df, dr)| enemy(file + df, rank + dr) == Some('N'))
+    {
+        return true;
+    }
+    if ROOK_DIRECTIONS
+        .iter()
+        .chain(&BISHOP_DIRECTIONS)
+        .any(|(df, dr)| enemy(file + df, rank + dr) == Some('K'))
+    {
+        return true;
+    }
+    for (directions, slider) in &[(ROOK_DIRECTIONS, 'R'), (BISHOP_DIRECTIONS, 'B')] {
+        for (df, dr) in directions {
+            let (mut f, mut r) = (file + df, rank + dr);
+            while (0..8).contains(&f) && (0..8).contains(&r) && at(board, f, r).is_none() {
+                f += df;
+                r += dr;
+            }
+            if matches!(enemy(f, r), Some(c) if c == *slider || c == 'Q') {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// Returns whether the side to move is in check.
+pub fn is_check(position: &Position) -> bool {
+    let board = parse_board(&position.to_fen()).expect("a position has a valid fen");
+    in_check(&board, position.side_to_move() == Color::WHITE)
+}
+
+fn letter(piece: PieceType) -> String {
+    piece.to_string().to_ascii_uppercase()
+}
+
+/// Returns the move in standard algebraic notation, e.g. `Nbd7`, `exd5`, `e8=Q+` or `O-O`.
+pub fn san(position: &Position, m: BitMove) -> String {
+    let mut san = if m.is_castle() {
+        if m.is_king_side_castle() {
+            "O-O".to_string()
+        } else {
+            "O-O-O".to_string()
+        }
+    } else {
+        let piece = position.get_square(m.origin()).piece_type();
+        let capture = if m.is_capture() || m.is_en_passant() {
+            "x"
+        } else {
+            ""
+        };
+        let origin = m.origin().to_string();
+        if piece == PieceType::PAWN {
+            let file = if capture.is_empty() { "" } else { &origin[..1] };
+            let mut san = format!("{}{}{}", file, capture, m.target());
+            if m.is_promotion() {
+                san += &format!("={}", letter(m.promotion_piece()));
+            }
+            san
+        } else {
+            // other pieces of the same kind that can reach the target
+            let others: Vec<Square> = position
+                .generate_legal_moves()
+                .into_iter()
+                .filter(|o| {
+                    o.target() == m.target()
+                        && o.origin() != m.origin()
+                        && position.get_square(o.origin()).piece_type() == piece
+                })
+                .map(|o| o.origin())
+                .collect();
+            let disambiguation = if others.is_empty() {
+                ""
+            } else if others.iter().all(|o| o.file() != m.origin().file()) {
+                &origin[..1]
+            } else if others.iter().all(|o| o.rank() != m.origin().rank()) {
+                &origin[1..]
+            } else {
+                &origin[..]
+            };
+            format!(
+                "{}{}{}{}",
+                letter(piece),
+                disambiguation,
+                capture,
+                m.target()
+            )
+        }
+    };
+
+    if let Ok(mut after) = Position::from_fen(&position.to_fen()) {
+        after.make_bit_move(m);
+        if after.is_checkmate() {
+            san.push('#');
+        } else if is_check(&after) {
+            san.push('+');
+        }
+    }
+    san
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let board =
+            parse_board("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1").unwrap();
+        assert_eq!(board[0], Some('R'));
+        assert_eq!(board[12], Some('P'));
+        assert_eq!(board[60], Some('k'));
+        assert_eq!(board[36], None);
+        assert_eq!(parse_board("8/8/8 w - - 0 1"), None);
+        assert_eq!(parse_board("9/8/8/8/8/8/8/8 w - - 0 1"), None);
+    }
+
+    #[test]
+    fn check() {
+        let check = |fen: &str| is_check(&Position::from_fen(fen).unwrap());
+        assert!(check("4k3/8/8/8/8/8/8/4R1K1 b - - 0 1"));
+        assert!(check("4k3/3P4/8/8/8/8/8/6K1 b - - 0 1"));
+        assert!(!check("4k3/3p4/8/8/8/8/8/6K1 w - - 0 1"));
+        assert!(!check("4k3/4p3/8/8/8/8/8/4R1K1 b - - 0 1"));
+        assert!(!check("4k3/8/8/8/8/8/8/1B4K1 b - - 0 1"));
+        assert!(check("4k3/8/8/8/B7/8/8/6K1 b - - 0 1"));
+        assert!(check("4k3/8/8/8/8/8/4n3/6K1 w - - 0 1"));
+    }
+}
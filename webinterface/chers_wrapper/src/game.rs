@@ -0,0 +1,191 @@
+//! A stateful game for the web interface, so that it knows the legal moves before they are sent to
+//! the robot.
+
+use wasm_bindgen::prelude::*;
+
+use chers::{BitMove, Color, ParsedMove, Position};
+use notation::san;
+
+use crate::{coordinate_notation, parse_fen};
+
+fn white_to_move(pos: &Position) -> bool {
+    pos.side_to_move() == Color::WHITE
+}
+
+/// A game with its history. Moves are given in coordinate notation, e.g. `e2e4` or `e7e8q`.
+#[wasm_bindgen]
+pub struct Game {
+    /// The fen of the position before the first move.
+    start: String,
+    moves: Vec<BitMove>,
+    san: Vec<String>,
+    position: Position,
+}
+
+impl Game {
+    fn find_move(&self, m: &str) -> Result<BitMove, JsValue> {
+        let parsed = ParsedMove::from_coordinate_notation(m)
+            .map_err(|_| JsValue::from_str(&format!("invalid move {}", m)))?;
+        self.position
+            .generate_legal_moves()
+            .into_iter()
+            .find(|&legal| legal == parsed)
+            .ok_or_else(|| JsValue::from_str(&format!("illegal move {}", m)))
+    }
+}
+
+#[wasm_bindgen]
+impl Game {
+    /// Starts a game from the given FEN or from the initial position. Throws if the FEN is invalid.
+    #[wasm_bindgen(constructor)]
+    pub fn new(fen: Option<String>) -> Result<Game, JsValue> {
+        let position = match &fen {
+            Some(fen) => parse_fen(fen)?,
+            None => Position::new(),
+        };
+        Ok(Game {
+            start: position.to_fen(),
+            moves: Vec::new(),
+            san: Vec::new(),
+            position,
+        })
+    }
+
+    /// Replaces the game by a new one starting at the given FEN. Throws if the FEN is invalid.
+    pub fn load_fen(&mut self, fen: &str) -> Result<(), JsValue> {
+        *self = Game::new(Some(fen.to_string()))?;
+        Ok(())
+    }
+
+    pub fn fen(&self) -> String {
+        self.position.to_fen()
+    }
+
+    /// `"white"` or `"black"`, like the colors of chessground.
+    pub fn turn(&self) -> String {
+        if white_to_move(&self.position) {
+            "white".to_string()
+        } else {
+            "black".to_string()
+        }
+    }
+
+    /// The legal moves in the format of the `movable.dests` option of chessground: a `Map` from
+    /// each origin square to an array of target squares.
+    pub fn dests(&self) -> js_sys::Map {
+        let dests = js_sys::Map::new();
+        for m in self.position.generate_legal_moves() {
+            let origin = JsValue::from_str(&m.origin().to_string());
+            let targets = match dests.get(&origin).dyn_into::<js_sys::Array>() {
+                Ok(targets) => targets,
+                Err(_) => {
+                    let targets = js_sys::Array::new();
+                    dests.set(&origin, &targets);
+                    targets
+                }
+            };
+            let target = JsValue::from_str(&m.target().to_string());
+            // promotions have the same squares for every piece
+            if !targets.includes(&target, 0) {
+                targets.push(&target);
+            }
+        }
+        dests
+    }
+
+    /// Returns whether the move, given without a piece like `e7e8`, is a promotion, so that the
+    /// piece has to be chosen.
+    pub fn is_promotion(&self, m: &str) -> bool {
+        self.position
+            .generate_legal_moves()
+            .into_iter()
+            .any(|legal| legal.is_promotion() && coordinate_notation(legal).starts_with(m))
+    }
+
+    /// Returns the move in standard algebraic notation. Throws if the move is illegal.
+    pub fn san(&self, m: &str) -> Result<String, JsValue> {
+        Ok(san(&self.position, self.find_move(m)?))
+    }
+
+    /// Makes the move and returns it in standard algebraic notation. Throws if the move is illegal.
+    pub fn make_move(&mut self, m: &str) -> Result<String, JsValue> {
+        let m = self.find_move(m)?;
+        let san = san(&self.position, m);
+        self.position.make_bit_move(m);
+        self.moves.push(m);
+        self.san.push(san.clone());
+        Ok(san)
+    }
+
+    /// Takes back the last move and returns it in coordinate notation or `undefined` if no move
+    /// has been made.
+    pub fn undo(&mut self) -> Option<String> {
+        let m = self.moves.pop()?;
+        self.san.pop();
+        // replaying the game keeps the history chers needs for detecting repetitions
+        self.position = Position::from_fen(&self.start).expect("the start position is valid");
+        for &m in &self.moves {
+            self.position.make_bit_move(m);
+        }
+        Some(coordinate_notation(m))
+    }
+
+    /// The moves made so far in standard algebraic notation.
+    pub fn history(&self) -> Box<[JsValue]> {
+        self.san.iter().map(|san| JsValue::from_str(san)).collect()
+    }
+
+    pub fn is_check(&self) -> bool {
+        notation::is_check(&self.position)
+    }
+
+    pub fn is_checkmate(&self) -> bool {
+        self.position.is_checkmate()
+    }
+
+    pub fn is_draw(&self) -> bool {
+        self.position.is_draw()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn san_of(fen: &str, m: &str) -> String {
+        let pos = Position::from_fen(fen).unwrap();
+        let parsed = ParsedMove::from_coordinate_notation(m).unwrap();
+        let m = pos
+            .generate_legal_moves()
+            .into_iter()
+            .find(|&legal| legal == parsed)
+            .unwrap();
+        san(&pos, m)
+    }
+
+    #[test]
+    fn check() {
+        let is_check = |fen: &str| Game::new(Some(fen.to_string())).unwrap().is_check();
+        assert!(is_check("4k3/8/8/8/8/8/8/4R1K1 b - - 0 1"));
+        assert!(is_check("4k3/3P4/8/8/8/8/8/6K1 b - - 0 1"));
+        assert!(!is_check("4k3/3p4/8/8/8/8/8/6K1 w - - 0 1"));
+        assert!(!is_check("4k3/4p3/8/8/8/8/8/4R1K1 b - - 0 1"));
+        assert!(!is_check("4k3/8/8/8/8/8/8/1B4K1 b - - 0 1"));
+        assert!(is_check("4k3/8/8/8/B7/8/8/6K1 b - - 0 1"));
+    }
+
+    #[test]
+    fn standard_algebraic_notation() {
+        let start = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+        assert_eq!(san_of(start, "e2e4"), "e4");
+        assert_eq!(san_of(start, "g1f3"), "Nf3");
+        let fen = "4k3/8/8/3p4/4P3/8/8/R3K2R w KQ - 0 1";
+        assert_eq!(san_of(fen, "e4d5"), "exd5");
+        assert_eq!(san_of(fen, "a1d1"), "Rd1");
+        assert_eq!(san_of(fen, "a1a8"), "Ra8+");
+        let fen = "4k3/8/8/8/8/8/4K3/R6R w - - 0 1";
+        assert_eq!(san_of(fen, "a1d1"), "Rad1");
+        let fen = "4k3/8/8/8/R7/8/4K3/R7 w - - 0 1";
+        assert_eq!(san_of(fen, "a1a2"), "R1a2");
+    }
+}
@@ -1,16 +1,214 @@
+mod game;
+
 use wasm_bindgen::prelude::*;
 
-use chers::Position;
+use chers::{BitMove, Position};
+
+pub use game::Game;
+
+/// The result of [`get_best_move`] and of each step of a [`Search`].
+///
+/// `chers` only returns the best move of a search and not its evaluation, so there is no score. The
+/// principal variation is found by searching the position after each of its moves again with one
+/// ply less.
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct SearchResult {
+    best_move: Option<String>,
+    mate: Option<i32>,
+    pv: Vec<String>,
+    depth: u32,
+}
 
 #[wasm_bindgen]
-pub fn get_best_move(fen: &str, depth: u32) -> String {
-    let fen = format!("{} b KQkq - 0 2", fen);
-    let mut pos = Position::from_fen(&fen).unwrap();
-    let m = pos.search(depth);
+impl SearchResult {
+    /// The best move in coordinate notation or `undefined` if there is no legal move.
+    #[wasm_bindgen(getter)]
+    pub fn best_move(&self) -> Option<String> {
+        self.best_move.clone()
+    }
 
+    /// The number of moves until the principal variation ends in checkmate, negative if the side
+    /// to move gets mated.
+    #[wasm_bindgen(getter)]
+    pub fn mate(&self) -> Option<i32> {
+        self.mate
+    }
+
+    /// The principal variation in coordinate notation.
+    #[wasm_bindgen(getter)]
+    pub fn pv(&self) -> Box<[JsValue]> {
+        self.pv.iter().map(|m| JsValue::from_str(m)).collect()
+    }
+
+    /// The depth that was searched.
+    #[wasm_bindgen(getter)]
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+}
+
+fn coordinate_notation(m: BitMove) -> String {
     if m.is_promotion() {
         format!("{}{}{}", m.origin(), m.target(), m.promotion_piece())
     } else {
         format!("{}{}", m.origin(), m.target())
     }
 }
+
+fn parse_fen(fen: &str) -> Result<Position, JsValue> {
+    Position::from_fen(fen).map_err(|_| JsValue::from_str(&format!("invalid fen {}", fen)))
+}
+
+/// Searches the position given as a complete FEN to the given depth. Throws if the FEN is invalid.
+#[wasm_bindgen]
+pub fn get_best_move(fen: &str, depth: u32) -> Result<SearchResult, JsValue> {
+    if depth == 0 {
+        return Err(JsValue::from_str("the depth must be positive"));
+    }
+    let mut pos = parse_fen(fen)?;
+    let best_move = search(&mut pos, depth);
+    Ok(variation(pos, best_move, depth))
+}
+
+/// Returns the best move at the given depth or `None` if the game is over.
+fn search(pos: &mut Position, depth: u32) -> Option<BitMove> {
+    if pos.is_checkmate() || pos.is_draw() {
+        return None;
+    }
+    Some(pos.search(depth)).filter(|&m| m != BitMove::NULL)
+}
+
+/// Returns the result of a search of the position to the given depth that found `best_move`. Its
+/// principal variation is searched with one ply less after each of its moves.
+fn variation(mut pos: Position, best_move: Option<BitMove>, depth: u32) -> SearchResult {
+    let mut pv = Vec::new();
+    let mut next = best_move;
+    while let Some(m) = next {
+        pv.push(coordinate_notation(m));
+        pos.make_bit_move(m);
+        let d = depth - pv.len() as u32;
+        next = if d == 0 { None } else { search(&mut pos, d) };
+    }
+
+    let mate = if pos.is_checkmate() {
+        // the side that made the last move of the variation has won
+        let moves = (pv.len() as i32 + 1) / 2;
+        Some(if pv.len() % 2 == 1 { moves } else { -moves })
+    } else {
+        None
+    };
+    SearchResult {
+        best_move: pv.first().cloned(),
+        mate,
+        pv,
+        depth,
+    }
+}
+
+/// A search with a time budget, which is deepened one ply per call of [`Search::step`]. Between the
+/// steps the caller stays responsive, e.g. a Web Worker can receive a message to cancel the search:
+///
+/// ```js
+/// const search = new Search(fen, 5000, 20, (result) => postMessage(...));
+/// function run() {
+///   if (search.step()) setTimeout(run);
+/// }
+/// ```
+///
+/// A step only searches the best move at its depth. `chers` can't interrupt a running search, so a
+/// step that has been started runs to its end, however long that takes, and the time budget and
+/// [`Search::cancel`] only take effect between the steps. The principal variation of the deepest
+/// step is searched once when the search ends, which takes a fraction of the time of that step.
+#[wasm_bindgen]
+pub struct Search {
+    fen: String,
+    max_depth: u32,
+    /// In milliseconds since the epoch.
+    deadline: f64,
+    on_progress: Option<js_sys::Function>,
+    /// The deepest completed step and the best move it found.
+    depth: u32,
+    best_move: Option<BitMove>,
+    result: Option<SearchResult>,
+    cancelled: bool,
+}
+
+#[wasm_bindgen]
+impl Search {
+    /// Starts a search of the position given as a complete FEN, which ends after `time` milliseconds
+    /// or at `max_depth`. `on_progress` is called with a [`SearchResult`] for every completed
+    /// depth, whose principal variation only holds the best move. Throws if the FEN is invalid.
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        fen: &str,
+        time: f64,
+        max_depth: u32,
+        on_progress: Option<js_sys::Function>,
+    ) -> Result<Search, JsValue> {
+        parse_fen(fen)?;
+        Ok(Search {
+            fen: fen.to_string(),
+            max_depth,
+            deadline: js_sys::Date::now() + time,
+            on_progress,
+            depth: 0,
+            best_move: None,
+            result: None,
+            cancelled: false,
+        })
+    }
+
+    /// Searches one ply deeper than before. Returns whether there is anything left to do, i.e. the
+    /// search hasn't been cancelled and neither the time budget nor the maximal depth is used up.
+    pub fn step(&mut self) -> Result<bool, JsValue> {
+        if !self.finished() {
+            let depth = self.depth + 1;
+            let best_move = search(&mut parse_fen(&self.fen)?, depth);
+            self.depth = depth;
+            self.best_move = best_move;
+            if let Some(on_progress) = &self.on_progress {
+                let best_move = best_move.map(coordinate_notation);
+                let result = SearchResult {
+                    best_move: best_move.clone(),
+                    mate: None,
+                    pv: best_move.into_iter().collect(),
+                    depth,
+                };
+                on_progress.call1(&JsValue::NULL, &result.into())?;
+            }
+            // there is nothing to search if the game is over
+            if best_move.is_some() && !self.finished() {
+                return Ok(true);
+            }
+        }
+        self.conclude()?;
+        Ok(false)
+    }
+
+    /// Stops the search and searches the principal variation of the last completed depth.
+    pub fn cancel(&mut self) -> Result<(), JsValue> {
+        self.cancelled = true;
+        self.conclude()
+    }
+
+    /// The result of the deepest completed step once the search has ended or `undefined` if it
+    /// hasn't ended or no step has completed.
+    #[wasm_bindgen(getter)]
+    pub fn result(&self) -> Option<SearchResult> {
+        self.result.clone()
+    }
+
+    fn finished(&self) -> bool {
+        self.cancelled || self.depth >= self.max_depth || js_sys::Date::now() >= self.deadline
+    }
+
+    /// Searches the principal variation, unless it has been searched already.
+    fn conclude(&mut self) -> Result<(), JsValue> {
+        if self.result.is_none() && self.depth > 0 {
+            let pos = parse_fen(&self.fen)?;
+            self.result = Some(variation(pos, self.best_move, self.depth));
+        }
+        Ok(())
+    }
+}
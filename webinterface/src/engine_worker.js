@@ -0,0 +1,70 @@
+// Runs the search of the wasm engine, so that the page stays responsive.
+//
+// Messages to the worker: { type: "start", fen, time, depth } and { type: "cancel" }.
+// Messages from the worker: { type: "progress", ...result } after every completed depth,
+// { type: "done", ...result } with the deepest result at the end or { type: "error", reason }.
+import init, { Search } from "../chers_wrapper/pkg/chers_wrapper.js";
+
+const ready = init();
+let search = null;
+
+function toObject(result) {
+  const obj = {
+    best_move: result.best_move,
+    mate: result.mate,
+    pv: Array.from(result.pv),
+    depth: result.depth,
+  };
+  result.free();
+  return obj;
+}
+
+function finish(current) {
+  if (search === current) {
+    search = null;
+  }
+  current.free();
+}
+
+function run(current) {
+  if (current !== search) {
+    // replaced by a newer search
+    return;
+  }
+  try {
+    if (current.step()) {
+      // give the worker the chance to receive a cancel message before the next depth
+      setTimeout(() => run(current));
+      return;
+    }
+  } catch (e) {
+    postMessage({ type: "error", reason: String(e) });
+    finish(current);
+    return;
+  }
+  const result = current.result;
+  postMessage({ type: "done", ...(result ? toObject(result) : {}) });
+  finish(current);
+}
+
+onmessage = async function (event) {
+  const msg = event.data;
+  await ready;
+  if (msg.type == "start") {
+    if (search) {
+      finish(search);
+    }
+    try {
+      search = new Search(msg.fen, msg.time, msg.depth, (result) =>
+        postMessage({ type: "progress", ...toObject(result) })
+      );
+    } catch (e) {
+      postMessage({ type: "error", reason: String(e) });
+      return;
+    }
+    const current = search;
+    setTimeout(() => run(current));
+  } else if (msg.type == "cancel" && search) {
+    search.cancel();
+  }
+};
@@ -1,11 +1,12 @@
 import { Chessground } from "chessground";
-import init, { get_best_move } from "../chers_wrapper/pkg/chers_wrapper.js";
+import init, { Game } from "../chers_wrapper/pkg/chers_wrapper.js";
+import { VERSION as protocol_version } from "./protocol.js";
 
 const config = {
   coordinates: false,
   movable: {
     color: "white", // only allow white pieces to be moved (it's white's turn to start)
-    free: true, // don't allow movement anywhere ...
+    free: false, // only allow the legal moves in `dests`
     events: {
       // called after the move has been played
       after: afterMove,
@@ -17,7 +18,7 @@ const config = {
 };
 
 const url = `ws://${location.hostname}:8080`;
-const subprotocol = "robochess-websocket";
+const subprotocol = "robochess-json";
 const container = document.getElementById("board-container");
 const slider_container = document.getElementById("slider-container");
 const slider = document.getElementById("depth-slider");
@@ -25,24 +26,158 @@ const depth_label = document.getElementById("depth-label");
 const input_button = document.getElementById("input");
 const calibrate_button = document.getElementById("calibrate");
 const reset_button = document.getElementById("reset");
+const undo_button = document.getElementById("undo");
+const pgn_button = document.getElementById("pgn");
+const import_input = document.getElementById("import");
+const analyse_button = document.getElementById("analyse");
+const placed_button = document.getElementById("placed");
+const analysis = document.getElementById("analysis");
+const progress = document.getElementById("progress");
+const replay = document.getElementById("replay");
+const replay_progress = document.getElementById("replay-progress");
+const replay_pause_button = document.getElementById("replay-pause");
+const replay_step_button = document.getElementById("replay-step");
+// seconds the analysis may take. chers can't interrupt a search, so the depth that is being
+// searched when the time is up is still finished and its principal variation searched, which can
+// take considerably longer. The button tells the user when that happens.
+const analysis_time = 5;
+const worker = new Worker(new URL("engine_worker.js", import.meta.url), {
+  type: "module",
+});
 
 let cg, socket;
+// knows the legal moves of the current position
+let chess;
 let depth;
+// the complete fen of the current position, `cg.getFen()` only contains the pieces
+let fen;
+let analysing = false;
+// tells the user once the analysis has used up `analysis_time`
+let analysis_timer;
+let next_id = 1;
+// only the client in control may move the pieces, the others only watch
+let control = false;
+// the square a promoted piece has to be put on by hand before the game goes on
+let awaited_piece = null;
+// whether the replay of an imported game is paused
+let replay_paused = false;
+// the type of every request that has not been answered yet by its id
+const pending = new Map();
+
+function send(type, params = {}) {
+  const id = next_id++;
+  pending.set(id, type);
+  socket.send(JSON.stringify({ id, type, ...params }));
+}
+
+function download(name, content) {
+  const link = document.createElement("a");
+  const blob = new Blob([content], { type: "application/x-chess-pgn" });
+  link.href = URL.createObjectURL(blob);
+  link.download = name;
+  link.click();
+  URL.revokeObjectURL(link.href);
+}
+
+function setGame(game) {
+  fen = game.fen;
+  chess.load_fen(game.fen);
+  cg.set({ fen: game.fen, ...config, viewOnly: !control });
+  updateMovable();
+  // the analysis belongs to the previous position
+  cg.setAutoShapes([]);
+  analysis.innerHTML = "";
+  cg.redrawAll();
+  setStatus(game.status);
+  awaited_piece = game.awaited_piece || null;
+  updatePlaced();
+  if (control && game.moving) {
+    // the robot failed in the middle of a move
+    rollMove(game.moving);
+  }
+}
+
+// the robot stopped in the middle of a move, which is either finished or taken back
+function rollMove(m) {
+  const forward = confirm(
+    `Den Zug ${m} zu Ende führen? Sonst wird er zurückgenommen.`
+  );
+  send("resume", { roll: forward ? "forward" : "back" });
+}
+
+// the server has been restarted in the middle of a game, which can be continued
+function offerSaved(saved) {
+  let question = `Die gespeicherte Partie nach ${saved.moves} Zügen fortsetzen?`;
+  if (saved.interrupted) {
+    question += `\nDer Roboter wurde unterbrochen (${saved.interrupted}), bitte die Figuren prüfen.`;
+  }
+  if (!confirm(question)) {
+    send("discard");
+  } else if (saved.moving) {
+    rollMove(saved.moving);
+  } else {
+    send("resume");
+  }
+}
+
+function showProgress({ done, total }) {
+  progress.innerHTML = done < total ? `Aufbau: ${done}/${total}` : "";
+}
+
+function showReplay({ played, total, paused }) {
+  replay.hidden = false;
+  replay_paused = paused;
+  replay_progress.innerHTML =
+    `Nachspielen: ${played}/${total}` + (paused ? " (pausiert)" : "");
+  replay_pause_button.innerHTML = paused ? "Weiter" : "Pause";
+  replay_step_button.disabled = !paused;
+}
+
+function updatePlaced() {
+  placed_button.hidden = !(control && awaited_piece);
+  if (awaited_piece) {
+    placed_button.innerHTML = `Figur auf ${awaited_piece} gestellt`;
+  }
+}
+
+function updateMovable() {
+  cg.set({
+    turnColor: chess.turn(),
+    check: chess.is_check(),
+    movable: { ...config.movable, dests: chess.dests() },
+  });
+}
+
+function setStatus(status) {
+  if (status == "checkmate") {
+    alert("Schachmatt!");
+    cg.stop();
+  } else if (status == "draw") {
+    alert("Unentschieden!");
+    cg.stop();
+  } else {
+    cg.set({ turnColor: status });
+  }
+}
 
 function updateDepth() {
   depth = slider.value;
   depth_label.innerHTML = depth;
+  if (control) {
+    // the server plays black
+    send("engine", { side: "black", depth: parseInt(depth) });
+  }
 }
 
 slider.oninput = updateDepth;
 
 input_button.onclick = function () {
   let m = prompt("Zug:");
-  socket.send(m);
+  send("move", { move: m });
 };
 
 calibrate_button.onclick = function () {
-  socket.send("!calibrate");
+  send("home");
 };
 
 reset_button.onclick = function () {
@@ -50,59 +185,196 @@ reset_button.onclick = function () {
     "Fen:",
     "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1"
   );
-  socket.send("!fen " + fen);
+  // the robot carries the pieces to their places
+  send("reset", { fen });
 };
 
-function afterMove(orig, dest, metadata) {
-  setTimeout(function () {
-    let m1 = orig + dest;
-    console.log("User move:", m1);
-    socket.send(m1);
+undo_button.onclick = function () {
+  // the engine plays black, so on white's turn its reply is taken back as well
+  send("undo", { moves: chess.turn() == "white" ? 2 : 1 });
+};
+
+pgn_button.onclick = function () {
+  send("pgn");
+};
+
+import_input.onchange = async function () {
+  const file = import_input.files[0];
+  if (file) {
+    // the robot sets up the start position and replays the moves
+    send("import", { pgn: await file.text() });
+    import_input.value = "";
+  }
+};
+
+replay_pause_button.onclick = function () {
+  send(replay_paused ? "replay_resume" : "replay_pause");
+};
 
-    let fen = cg.getFen();
-    let m2 = get_best_move(fen, depth);
-    console.log("Response: ", m2);
-    socket.send(m2);
+replay_step_button.onclick = function () {
+  send("replay_step");
+};
 
-    let from = m2.substr(0, 2);
-    let to = m2.substr(2, 2);
+placed_button.onclick = function () {
+  send("placed");
+};
 
-    cg.move(from, to);
-    cg.set({ turnColor: "black" });
+analyse_button.onclick = function () {
+  if (analysing) {
+    worker.postMessage({ type: "cancel" });
+    return;
+  }
+  analysing = true;
+  analyse_button.innerHTML = "Abbrechen";
+  clearTimeout(analysis_timer);
+  analysis_timer = setTimeout(function () {
+    if (analysing) {
+      analyse_button.innerHTML = "Abbrechen (letzte Tiefe läuft noch)";
+    }
+  }, analysis_time * 1000);
+  worker.postMessage({
+    type: "start",
+    fen,
+    time: analysis_time * 1000,
+    depth: 20,
   });
-  cg.redrawAll();
+};
+
+function showAnalysis(result) {
+  if (!result.best_move) {
+    analysis.innerHTML = "";
+    cg.setAutoShapes([]);
+    return;
+  }
+  let mate = result.mate != null ? `#${result.mate} ` : "";
+  analysis.innerHTML = `Tiefe ${result.depth}: ${mate}${result.pv.join(" ")}`;
+  let m = result.best_move;
+  cg.setAutoShapes([
+    { orig: m.substr(0, 2), dest: m.substr(2, 2), brush: "green" },
+  ]);
+}
+
+worker.onmessage = function (event) {
+  let msg = event.data;
+  if (msg.type == "error") {
+    console.error("Analysis failed:", msg.reason);
+  } else {
+    showAnalysis(msg);
+  }
+  if (msg.type != "progress") {
+    analysing = false;
+    clearTimeout(analysis_timer);
+    analyse_button.innerHTML = "Analysieren";
+  }
+};
+
+function afterMove(orig, dest, metadata) {
+  let m = orig + dest;
+  let promotion = chess.is_promotion(m);
+  if (promotion) {
+    m += prompt("Umwandeln in (q, r, b, n):", "q");
+  }
+  try {
+    console.log("User move:", chess.make_move(m));
+  } catch (e) {
+    console.error(e);
+    cg.set({ fen: chess.fen() });
+    updateMovable();
+    return;
+  }
+  // chessground only moved the piece, so it still shows a promoted pawn or a pawn captured en
+  // passant
+  if (cg.getFen() != chess.fen().split(" ")[0]) {
+    cg.set({ fen: chess.fen() });
+  }
+  updateMovable();
+  send("move", { move: m });
+  // the reply of the engine arrives as a new state
 }
 
 (async function () {
   // wasm stuff
   await init();
+  chess = new Game();
   updateDepth();
 
   socket = new WebSocket(url, subprotocol);
 
   // Listen for messages
   socket.addEventListener("message", function (event) {
-    let msg = event.data;
-    console.log("Message from server:", msg);
-    if (msg.startsWith("!")) {
-      let commands = msg.split(" ");
-      if (commands[0] == "!set") {
-        let fen = commands[1];
-        cg.set({ fen, ...config });
-        cg.redrawAll();
-      } else if (commands[0] == "!checkmate") {
-        alert("Schachmatt!");
-        cg.stop();
-      } else if (commands[0] == "!draw") {
-        alert("Unentschieden!");
-        cg.stop();
-      } else if (commands[0] == "!white") {
-        cg.set({ turnColor: "white" });
-      } else if (commands[0] == "!black") {
-        cg.set({ turnColor: "black" });
+    console.log("Message from server:", event.data);
+    let msg = JSON.parse(event.data);
+    if (msg.type == "state") {
+      // another client or the engine changed the game
+      setGame(msg);
+      return;
+    } else if (msg.type == "notice") {
+      alert(msg.text);
+      return;
+    } else if (msg.type == "progress") {
+      showProgress(msg);
+      return;
+    } else if (msg.type == "replay") {
+      showReplay(msg);
+      return;
+    } else if (msg.type == "control") {
+      control = true;
+      cg.set({ viewOnly: false });
+      updateDepth();
+      updatePlaced();
+      return;
+    }
+    if (msg.type == "error" && msg.id == null) {
+      // the robot failed on its own, e.g. while playing the move of the engine
+      alert(msg.reason);
+      send("state");
+      return;
+    }
+    let type = pending.get(msg.id);
+    pending.delete(msg.id);
+    if (msg.type == "error") {
+      console.error(`${type} failed:`, msg.reason);
+      if (type == "hello") {
+        container.innerHTML = "Error: " + msg.reason;
+      } else if (type == "move" || type == "fen") {
+        // reset the board to the position of the server
+        send("state");
+      } else if (
+        ["reset", "undo", "import", "resume", "discard"].includes(type) ||
+        type.startsWith("replay_")
+      ) {
+        alert(msg.reason);
+      }
+      return;
+    }
+    let result = msg.result;
+    if (type == "hello") {
+      control = result.control;
+      setGame(result);
+      updateDepth();
+      if (control && result.saved) {
+        offerSaved(result.saved);
+      }
+    } else if (type == "reset" || type == "undo" || type == "import") {
+      // a new game ends the replay
+      replay.hidden = type != "import";
+      showProgress(result);
+    } else if (type == "replay_step" && result) {
+      setGame(result);
+    } else if (type == "pgn") {
+      download("robochess.pgn", result.pgn);
+    } else if (["state", "fen", "placed", "resume"].includes(type)) {
+      setGame(result);
+    } else if (type == "move") {
+      if (result.fen != chess.fen()) {
+        // the move was entered by hand instead of on the board
+        setGame(result);
+      } else {
+        fen = result.fen;
+        setStatus(result.status);
+        awaited_piece = result.awaited_piece || null;
+        updatePlaced();
       }
-    } else if (msg != "OK") {
-      console.error(msg);
     }
   });
 
@@ -116,5 +388,6 @@ function afterMove(orig, dest, metadata) {
     console.log("Connected!");
     slider_container.style.visibility = "visible";
     cg = Chessground(container, config);
+    send("hello", { version: protocol_version });
   });
 })();
@@ -0,0 +1,3 @@
+// The version of the json protocol of the controller. It has to match `VERSION` in
+// controller/src/protocol.rs, which checks it in its tests.
+export const VERSION = 1;